@@ -1,32 +1,52 @@
-use crate::reg::Reg;
-use crate::{board, cpu, msr_imm, println};
+// The boot, exception and power-off paths here are only reachable
+// from _start, so most of this module is dead on the host.
+#![cfg_attr(not(target_os = "none"), allow(dead_code))]
+
+use crate::reg::RegWrite;
+use crate::{cpu, msr_imm, semihosting};
+#[cfg(target_os = "none")]
+use crate::{board, trap};
+#[cfg(target_os = "none")]
 use core::arch::{asm, global_asm};
 
 // halt spins forever.
+// On the host there is nothing to wait for, so it panics.
 pub fn halt() -> ! {
+    #[cfg(not(target_os = "none"))]
+    panic!("halt");
+    #[cfg(target_os = "none")]
     loop {
         unsafe { asm!("wfe") }
     }
 }
 
-// power_off shuts down using qemu semihosting feature.
-pub fn power_off() -> ! {
-    // TODO: any way to affect the exit code?
-    // I always get exit(1) from qemu, even with other exit reasons.
+// wfe waits for an event (or interrupt).
+pub fn wfe() {
+    #[cfg(target_os = "none")]
     unsafe {
-        //asm!("svc 0"); // test exceptions.
-        //unsafe { core::ptr::write_volatile(0x0000_3fff_ffff_ffff as *mut u64, 1); } // test exceptions
+        asm!("wfe")
+    }
+}
 
-        asm!(
-            "mov w0, 0x18",     // exit
-            "ldr x1, =0x20026", // application exit
-            "hlt #0xF000",      // semihosting call
-        )
+// sev signals an event to all cores.
+pub fn sev() {
+    #[cfg(target_os = "none")]
+    unsafe {
+        asm!("sev")
     }
-    halt();
+}
+
+// power_off shuts down using qemu semihosting feature.
+pub fn power_off() -> ! {
+    //unsafe { asm!("svc 0"); } // test exceptions.
+    //unsafe { core::ptr::write_volatile(0x0000_3fff_ffff_ffff as *mut u64, 1); } // test exceptions
+    semihosting::exit(0);
 }
 
 pub fn delay(cycles: u64) {
+    #[cfg(not(target_os = "none"))]
+    let _ = cycles;
+    #[cfg(target_os = "none")]
     unsafe {
         asm!(
             "1:",
@@ -37,123 +57,283 @@ pub fn delay(cycles: u64) {
     }
 }
 
+// _vector_table is installed at every EL we pass through.
+// Each vector saves x0/x1 in a new TrapFrame on the stack, loads
+// (group << 4 | kind) into x0 and branches to _trap_entry for the rest.
+// Groups are: 0 current EL with SP0, 1 current EL with SPx,
+// 2 lower EL AArch64, 3 lower EL AArch32.
+// Kinds are: 0 synchronous, 1 IRQ, 2 FIQ, 3 SError.
+#[cfg(target_os = "none")]
 global_asm!(
     "
+    .macro trap_vector num
+    .balign 128
+        sub sp, sp, #{frame_size}
+        stp x0, x1, [sp, #0]
+        mov x0, #\\num
+        b _trap_entry
+    .endm
+
     .global _vector_table
     .balign 2048
     _vector_table:
+        trap_vector 0x00
+        trap_vector 0x01
+        trap_vector 0x02
+        trap_vector 0x03
+        trap_vector 0x10
+        trap_vector 0x11
+        trap_vector 0x12
+        trap_vector 0x13
+        trap_vector 0x20
+        trap_vector 0x21
+        trap_vector 0x22
+        trap_vector 0x23
+        trap_vector 0x30
+        trap_vector 0x31
+        trap_vector 0x32
+        trap_vector 0x33
 
-    // Lower EL, AArch32
-    .balign 128
-    _vector_0_serror:
-        mov x0, #0x0
-        b _unhandled_exception
-    .balign 128
-    _vector_0_fiq:
-        mov x0, #0x1
-        b _unhandled_exception
-    .balign 128
-    _vector_0_irq:
-        mov x0, #0x2
-        b _unhandled_exception
-    .balign 128
-    _vector_0_synch:
-        mov x0, #0x3
-        b _unhandled_exception
+    // _trap_entry finishes filling in the TrapFrame, calls
+    // _trap_dispatch(num, frame), then restores the (possibly
+    // modified) frame and returns from the exception.
+    // The offsets here must match the layout of trap::TrapFrame.
+    _trap_entry:
+        stp x2, x3, [sp, #16]
+        stp x4, x5, [sp, #32]
+        stp x6, x7, [sp, #48]
+        stp x8, x9, [sp, #64]
+        stp x10, x11, [sp, #80]
+        stp x12, x13, [sp, #96]
+        stp x14, x15, [sp, #112]
+        stp x16, x17, [sp, #128]
+        stp x18, x19, [sp, #144]
+        stp x20, x21, [sp, #160]
+        stp x22, x23, [sp, #176]
+        stp x24, x25, [sp, #192]
+        stp x26, x27, [sp, #208]
+        stp x28, x29, [sp, #224]
+        mrs x1, SP_EL0
+        stp x30, x1, [sp, #240]
 
-    // Lower EL, AArch64
-    .balign 128
-    _vector_1_serror:
-        mov x0, #0x10
-        b _unhandled_exception
-    .balign 128
-    _vector_1_fiq:
-        mov x0, #0x11
-        b _unhandled_exception
-    .balign 128
-    _vector_1_irq:
-        mov x0, #0x12
-        b _unhandled_exception
-    .balign 128
-    _vector_1_synch:
-        mov x0, #0x13
-        b _unhandled_exception
+        mrs x1, CurrentEL
+        cmp x1, #(2 << 2)
+        b.eq 2f
+        b.hi 3f
+        mrs x2, ELR_EL1
+        mrs x3, SPSR_EL1
+        b 4f
+    2:
+        mrs x2, ELR_EL2
+        mrs x3, SPSR_EL2
+        b 4f
+    3:
+        mrs x2, ELR_EL3
+        mrs x3, SPSR_EL3
+    4:
+        stp x2, x3, [sp, #256]
 
-    // Current EL, SPx
-    .balign 128
-    _vector_2_serror:
-        mov x0, #0x20
-        b _unhandled_exception
-    .balign 128
-    _vector_2_fiq:
-        mov x0, #0x21
-        b _unhandled_exception
-    .balign 128
-    _vector_2_irq:
-        mov x0, #0x22
-        b _unhandled_exception
-    .balign 128
-    _vector_2_synch:
-        mov x0, #0x23
-        b _unhandled_exception
+        mov x1, sp
+        bl _trap_dispatch
 
-    // Current EL, SP0
-    .balign 128
-    _vector_3_serror:
-        mov x0, #0x30
-        b _unhandled_exception
-    .balign 128
-    _vector_3_fiq:
-        mov x0, #0x31
-        b _unhandled_exception
-    .balign 128
-    _vector_3_irq:
-        mov x0, #0x32
-        b _unhandled_exception
-    .balign 128
-    _vector_3_synch:
-        mov x0, #0x33
-        b _unhandled_exception
-"
+        ldp x2, x3, [sp, #256]
+        mrs x1, CurrentEL
+        cmp x1, #(2 << 2)
+        b.eq 2f
+        b.hi 3f
+        msr ELR_EL1, x2
+        msr SPSR_EL1, x3
+        b 4f
+    2:
+        msr ELR_EL2, x2
+        msr SPSR_EL2, x3
+        b 4f
+    3:
+        msr ELR_EL3, x2
+        msr SPSR_EL3, x3
+    4:
+        ldp x30, x1, [sp, #240]
+        msr SP_EL0, x1
+        ldp x28, x29, [sp, #224]
+        ldp x26, x27, [sp, #208]
+        ldp x24, x25, [sp, #192]
+        ldp x22, x23, [sp, #176]
+        ldp x20, x21, [sp, #160]
+        ldp x18, x19, [sp, #144]
+        ldp x16, x17, [sp, #128]
+        ldp x14, x15, [sp, #112]
+        ldp x12, x13, [sp, #96]
+        ldp x10, x11, [sp, #80]
+        ldp x8, x9, [sp, #64]
+        ldp x6, x7, [sp, #48]
+        ldp x4, x5, [sp, #32]
+        ldp x2, x3, [sp, #16]
+        ldp x0, x1, [sp, #0]
+        add sp, sp, #{frame_size}
+        eret
+",
+    frame_size = const trap::FRAME_SIZE,
 );
 
+#[cfg(target_os = "none")]
 extern "C" {
     fn _vector_table();
 }
 
-// _unhandled_exception is called by the cpu via vector_table to handle exceptions.
-#[no_mangle]
-pub extern "C" fn _unhandled_exception(num: u64) -> ! {
-    let group = num >> 4;
-    let index = num & 0xf;
-    let elr = cpu::ElrEl3::fetch().get_value();
-    let esr = cpu::EsrEl3::fetch().get_value();
-    let far = cpu::FarEl3::fetch().get_value();
-    println!("got exception group {} index {}", group, index);
-    println!("  ELR {:x} ESR {:x} FAR {:x}", elr, esr, far);
-    panic!("unhandled exception");
-}
-
+// init_exceptions installs the vector table and unmasks exceptions
+// at whatever EL we ended up running at.
 pub fn init_exceptions() {
+    #[cfg(target_os = "none")]
     let vbar = _vector_table as u64;
-    cpu::ScrEl3::zero()
-        .set_ea(true) // EA unmasked
-        .set_irq(true) // IRQ unmasked
-        .set_fiq(true) // FIQ unmasked
-        .set_rw(true) // RW - next level AArch64
-        .store();
+    #[cfg(not(target_os = "none"))]
+    let vbar = 0;
+    match cpu::current_el() {
+        3 => {
+            cpu::ScrEl3::zero()
+                .set_ea(true) // EA unmasked
+                .set_irq(true) // IRQ unmasked
+                .set_fiq(true) // FIQ unmasked
+                .set_rw(true) // RW - next level AArch64
+                .store();
+        }
+        2 => {
+            cpu::HcrEl2::zero().modify(|r| {
+                r.set_amo(true) // route SError to EL2
+                    .set_imo(true) // route IRQ to EL2
+                    .set_fmo(true) // route FIQ to EL2
+            });
+        }
+        _ => {}
+    }
     cpu::SpSel::zero()
         .set_sp(true) // SP - use SP_ELx for exceptions, not SP_EL0
         .store();
-    cpu::VBarEl3::new(vbar).store();
+    cpu::set_vbar(vbar);
     msr_imm!(DAIFClr, 0b1111); // clear interrupt disables
 }
 
+// Register values used by _drop_el.
+// SCR_EL3: NS, RES1 bits, SMD (no smc), HCE (allow hvc), RW (EL2 is AArch64).
+#[cfg(target_os = "none")]
+const SCR_EL3_DROP: u64 = 1 << 0 | 0b11 << 4 | 1 << 7 | 1 << 8 | 1 << 10;
+// HCR_EL2: RW (EL1 is AArch64).
+#[cfg(target_os = "none")]
+const HCR_EL2_DROP: u64 = 1 << 31;
+// CPTR_EL2: RES1 bits only, so FP/SIMD isn't trapped.
+#[cfg(target_os = "none")]
+const CPTR_EL2_DROP: u64 = 0x33ff;
+// SCTLR_ELx: RES1 bits only, MMU and caches off, little endian.
+#[cfg(target_os = "none")]
+const SCTLR_EL2_DROP: u64 = 0x30c5_0830;
+#[cfg(target_os = "none")]
+const SCTLR_EL1_DROP: u64 = 0x30d0_0800;
+// CPACR_EL1: FPEN, don't trap FP/SIMD at EL1 or EL0.
+#[cfg(target_os = "none")]
+const CPACR_EL1_DROP: u64 = 0b11 << 20;
+// SPSR: return to ELxh (using SP_ELx) with DAIF masked.
+#[cfg(target_os = "none")]
+const SPSR_EL2H: u64 = 0x3c9;
+#[cfg(target_os = "none")]
+const SPSR_EL1H: u64 = 0x3c5;
+
+// _drop_el lowers the exception level to the target EL in x0 and
+// returns to the caller (in x30) at that level, keeping the same stack.
+// It installs _vector_table at every level it passes through, and
+// configures EL2 even when stopping there, so the kernel can run at
+// EL1 under a later hypervisor layer or at EL2 as one.
+#[cfg(target_os = "none")]
+global_asm!(
+    "
+    .global _drop_el
+    _drop_el:
+        mrs x1, CurrentEL
+        lsr x1, x1, #2
+        cmp x1, #3
+        b.ne 2f
+        cmp x0, #3
+        b.hs 9f
+
+        // EL3 -> EL2
+        ldr x2, =_vector_table
+        msr VBAR_EL3, x2
+        ldr x2, ={scr_el3}
+        msr SCR_EL3, x2
+        mov x2, sp
+        msr SP_EL2, x2
+        ldr x2, =_drop_el       // start over at EL2
+        msr ELR_EL3, x2
+        ldr x2, ={spsr_el2h}
+        msr SPSR_EL3, x2
+        eret
+
+    2:
+        cmp x1, #2
+        b.ne 9f
+
+        // EL2 setup, used whether or not we go any lower.
+        ldr x2, =_vector_table
+        msr VBAR_EL2, x2
+        ldr x2, ={sctlr_el2}
+        msr SCTLR_EL2, x2
+        ldr x2, ={hcr_el2}
+        msr HCR_EL2, x2
+        ldr x2, ={cptr_el2}
+        msr CPTR_EL2, x2
+        msr HSTR_EL2, xzr
+        mov x2, #3              // EL1PCEN | EL1PCTEN, EL1 timer access
+        msr CNTHCTL_EL2, x2
+        msr CNTVOFF_EL2, xzr
+        cmp x0, #2
+        b.hs 9f
+
+        // EL2 -> EL1
+        ldr x2, =_vector_table
+        msr VBAR_EL1, x2
+        ldr x2, ={sctlr_el1}
+        msr SCTLR_EL1, x2
+        ldr x2, ={cpacr_el1}
+        msr CPACR_EL1, x2
+        mov x2, sp
+        msr SP_EL1, x2
+        msr ELR_EL2, x30        // return to caller at EL1
+        ldr x2, ={spsr_el1h}
+        msr SPSR_EL2, x2
+        eret
+
+    9:
+        ret
+",
+    scr_el3 = const SCR_EL3_DROP,
+    hcr_el2 = const HCR_EL2_DROP,
+    cptr_el2 = const CPTR_EL2_DROP,
+    sctlr_el2 = const SCTLR_EL2_DROP,
+    sctlr_el1 = const SCTLR_EL1_DROP,
+    cpacr_el1 = const CPACR_EL1_DROP,
+    spsr_el2h = const SPSR_EL2H,
+    spsr_el1h = const SPSR_EL1H,
+);
+
+// _bss_zeroed is set by core 0 once .bss has been cleared.
+// It lives in .data so that clearing .bss doesn't clobber it.
+#[cfg(target_os = "none")]
+global_asm!(
+    "
+    .section .data
+    .balign 8
+    _bss_zeroed:
+        .quad 0
+"
+);
+
 // _start is the initial entry point.
 // Qemu calls it on all four cores, with no stack pointer set.
-// It sets up a stack for each core and tail calls _start_rust.
+// It sets up a stack for each core, has core 0 zero .bss while
+// the other cores wait for it, drops to board::KERNEL_EL, and
+// tail calls _start_rust.
+#[cfg(target_os = "none")]
 #[no_mangle]
 #[naked]
+#[link_section = ".text.boot"]
 pub extern "C" fn _start() -> ! {
     unsafe {
         asm!(
@@ -163,9 +343,32 @@ pub extern "C" fn _start() -> ! {
             "ldr x2, ={ram_top}",
             "msub x30, x0, x1, x2",
             "mov sp, x30             // sp = ram_top - core_id * stack_size",
+            "ldr x1, =_bss_zeroed",
+            "cbnz x0, 3f",
+            "ldr x2, =__bss_start",
+            "ldr x3, =__bss_end",
+            "1:",
+            "cmp x2, x3",
+            "b.hs 2f",
+            "stp xzr, xzr, [x2], #16",
+            "b 1b",
+            "2:",
+            "mov x2, #1",
+            "stlr x2, [x1]           // publish the zeroed .bss",
+            "sev",
+            "b 4f",
+            "3:",
+            "ldar x2, [x1]",
+            "cbnz x2, 4f",
+            "wfe                     // wait for core 0",
+            "b 3b",
+            "4:",
+            "mov x0, #{kernel_el}",
+            "bl _drop_el",
             "b _start_rust",
             stack_size = const board::STACK_SIZE,
             ram_top = const board::RAM_TOP,
+            kernel_el = const board::KERNEL_EL,
             options(noreturn),
         );
     }
@@ -1,6 +1,16 @@
 pub const NCPU: usize = 4;
+#[cfg(target_os = "none")]
 pub const STACK_SIZE: usize = 0x10000;
 
+// TICK_HZ is the rate of the per-core timer tick interrupt.
+pub const TICK_HZ: u64 = 100;
+
+// KERNEL_EL is the exception level the kernel runs at.
+// Qemu starts us at EL3 and _start drops down to this level.
+// Valid values are 1, 2 or 3.
+#[cfg(target_os = "none")]
+pub const KERNEL_EL: u64 = 1;
+
 /*
  * BCM2837 phys IOBASE
  * bus address 0x7Exx.xxxx lives at cpu phys address 0x3Fxx.xxxx
@@ -9,8 +19,13 @@ pub const STACK_SIZE: usize = 0x10000;
 pub const IOBASE: usize = 0x3f00_0000;
 pub const AUX_BASE: usize = IOBASE + 0x21_5000;
 pub const GPIO_BASE: usize = IOBASE + 0x20_0000;
-#[allow(dead_code)]
 pub const INTC_BASE: usize = IOBASE + 0x00_B000;
+pub const SYSTIMER_BASE: usize = IOBASE + 0x00_3000;
+
+// BCM2836 per-core local peripherals (local interrupt controller,
+// mailboxes, core timer routing). Not part of IOBASE.
+// Ref: BCM2836 ARM-local peripherals (QA7).
+pub const LOCAL_BASE: usize = 0x4000_0000;
 
 // RAMTOP is at 0x4000_0000, but overlaps the IO region at 0x3f00_0000.
 // During boot it some ram is stolen for the VC SDRAM which specifies a
@@ -19,15 +34,50 @@ pub const INTC_BASE: usize = IOBASE + 0x00_B000;
 // but in my qemu environment I've got nothing.
 // So.. wild guesses here, lets say the VC SDRAM split gives 256MB to the GPU,
 // and we'll claim the rest.
+#[cfg(target_os = "none")]
 pub const RAM_TOP: usize = 0x4000_0000 - 256 * 1024 * 1024;
 
-// I'm not using a linker script yet, and I have no access to a symbol
-// specifying the end of the .bss section...  So lets fake it.
-// Arbitrarily declaring that the text/data/bss will fit in 64MB!
-pub const PROG_SIZE: usize = 64 * 1024 * 1024;
-pub const HEAP_BASE: usize = 0 + PROG_SIZE;
+// The heap runs from the end of the kernel image (text/data/bss, as laid
+// out by link.ld) up to the per-core stacks at the top of ram.
+#[cfg(target_os = "none")]
 pub const HEAP_TOP: usize = RAM_TOP - STACK_SIZE * NCPU;
 
+#[cfg(target_os = "none")]
+extern "C" {
+    static __kernel_end: u8;
+}
+
+#[cfg(target_os = "none")]
+pub fn heap_base() -> usize {
+    unsafe { &__kernel_end as *const u8 as usize }
+}
+
+pub const PL011_BASE: usize = IOBASE + 0x20_1000;
+
+// CONSOLE is the uart used for the kernel console, unless the command
+// line picks another (see console::select_from_cmdline).
+// Qemu's runner puts the AUX uart on stdio, see the qemu script
+// for running with the PL011 instead.
+pub const CONSOLE: crate::console::Backend = crate::console::Backend::Aux;
+
+// CONSOLE_FROM_CMDLINE reads the console from the semihosting command
+// line at boot. Turn it off on hardware with no semihosting host.
+#[cfg(target_os = "none")]
+pub const CONSOLE_FROM_CMDLINE: bool = true;
+
+// LOG_LEVEL is the most verbose level logged, unless overridden for a
+// module with log::set_module_level.
+pub const LOG_LEVEL: crate::log::Level = crate::log::Level::Info;
+
+// TRACE_REGS is the peripherals whose register accesses are traced
+// when built with the trace-regs feature, see regtrace.rs. The console
+// uart is left out, as polling it would fill the log.
+#[cfg(all(feature = "trace-regs", not(test)))]
+pub const TRACE_REGS: u32 = crate::regtrace::ALL & !crate::regtrace::AUX;
+
 pub const AUX_UART_CLOCK: u32 = 50_000_000;
 pub const AUX_UART_TX_PIN: u32 = 14;
 pub const AUX_UART_RX_PIN: u32 = 15;
+pub const PL011_CLOCK: u32 = 48_000_000;
+pub const PL011_TX_PIN: u32 = 14;
+pub const PL011_RX_PIN: u32 = 15;
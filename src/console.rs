@@ -0,0 +1,292 @@
+/*
+ * console.rs
+ * Kernel console, on top of one of the uarts.
+ *
+ * print! and println! write to the console, and the read functions
+ * take input from it. The backend is picked with board::CONSOLE, and
+ * at boot select_from_cmdline switches to the one named by a
+ * console=aux or console=pl011 word on the semihosting command line.
+ */
+
+use crate::{asm, board, cpu, pl011, print, uart};
+use core::fmt;
+use lazy_static::lazy_static;
+use spin::{Mutex, MutexGuard};
+
+// Console is a uart that can be used as the console.
+pub trait Console: Sync {
+    // init sets the device up for polled output.
+    fn init(&self);
+
+    // init_irq turns on receive interrupts.
+    // It is called after irq::init.
+    fn init_irq(&self);
+
+    // write_byte writes a single byte.
+    fn write_byte(&self, ch: u8);
+
+    // flush waits until everything written has been sent.
+    fn flush(&self);
+
+    // try_read_byte returns the next received byte, if there is one.
+    // Only one reader may call it at a time.
+    fn try_read_byte(&self) -> Option<u8>;
+
+    // recover reinitializes the device if it is not set up to transmit.
+    // It is used before emergency output.
+    fn recover(&self);
+
+    // emergency_write_byte writes a byte by polling, without waiting on
+    // any locks. It is only used with interrupts masked.
+    fn emergency_write_byte(&self, ch: u8);
+}
+
+// Backend names the available consoles.
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backend {
+    // The mini uart (uart1), qemu's second serial port.
+    Aux,
+    // The PL011 (uart0), qemu's first serial port.
+    Pl011,
+}
+
+impl Backend {
+    fn device(&self) -> &'static dyn Console {
+        match self {
+            Backend::Aux => &uart::AUX_UART,
+            Backend::Pl011 => &pl011::PL011,
+        }
+    }
+}
+
+pub struct Writer {
+    console: &'static dyn Console,
+    initialized: bool,
+}
+
+lazy_static! {
+    pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
+        console: board::CONSOLE.device(),
+        initialized: false,
+    });
+}
+
+impl Writer {
+    // init initializes the console the first time it is called.
+    fn init(&mut self) {
+        if !self.initialized {
+            self.initialized = true;
+            self.console.init();
+        }
+    }
+}
+
+impl fmt::Write for Writer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.init();
+
+        for ch in s.bytes() {
+            self.console.write_byte(ch);
+        }
+        Ok(())
+    }
+}
+
+// select switches the console to backend.
+// It should be called early, before init_irq.
+#[allow(dead_code)]
+pub fn select(backend: Backend) {
+    let mut writer = WRITER.lock();
+    writer.console = backend.device();
+    writer.initialized = false;
+}
+
+// cmdline_backend returns the backend named by a console= word in cmdline.
+#[cfg(any(target_os = "none", test))]
+fn cmdline_backend(cmdline: &str) -> Option<Backend> {
+    let mut backend = None;
+    for word in cmdline.split_whitespace() {
+        match word {
+            "console=aux" => backend = Some(Backend::Aux),
+            "console=pl011" => backend = Some(Backend::Pl011),
+            _ => {}
+        }
+    }
+    backend
+}
+
+// select_from_cmdline selects the console named on the semihosting
+// command line (qemu's -append), if any. It must be called before
+// anything is printed.
+#[cfg(target_os = "none")]
+pub fn select_from_cmdline() {
+    if !board::CONSOLE_FROM_CMDLINE {
+        return;
+    }
+    let mut buf = [0u8; 256];
+    if let Some(backend) = crate::semihosting::get_cmdline(&mut buf)
+        .ok()
+        .and_then(cmdline_backend)
+    {
+        select(backend);
+    }
+}
+
+// init_irq turns on console input.
+// It must be called after irq::init.
+#[cfg_attr(not(target_os = "none"), allow(dead_code))]
+pub fn init_irq() {
+    let mut writer = WRITER.lock();
+    writer.init();
+    writer.console.init_irq();
+}
+
+// flush waits for console output to be sent, so nothing is lost
+// when powering off.
+#[cfg_attr(not(target_os = "none"), allow(dead_code))]
+pub fn flush() {
+    let console = WRITER.lock().console;
+    console.flush();
+}
+
+// READER serializes consumers of the console's receive buffer.
+static READER: Mutex<()> = Mutex::new(());
+
+// try_read_byte returns the next received byte, if there is one.
+#[allow(dead_code)]
+pub fn try_read_byte() -> Option<u8> {
+    let console = WRITER.lock().console;
+    let _reader = READER.lock();
+    console.try_read_byte()
+}
+
+// read_byte waits for and returns the next received byte.
+#[allow(dead_code)]
+pub fn read_byte() -> u8 {
+    loop {
+        if let Some(ch) = try_read_byte() {
+            return ch;
+        }
+        asm::wfe(); // receive handlers sev
+    }
+}
+
+// read_line reads and echoes a line into buf, handling backspace,
+// until enter is pressed or buf is full.
+// It returns the number of bytes read, not including the line ending.
+#[allow(dead_code)]
+pub fn read_line(buf: &mut [u8]) -> usize {
+    let mut n = 0;
+    while n < buf.len() {
+        match read_byte() {
+            b'\r' | b'\n' => {
+                print!("\n");
+                break;
+            }
+            0x08 | 0x7f => {
+                if n > 0 {
+                    n -= 1;
+                    print!("\x08 \x08");
+                }
+            }
+            ch => {
+                buf[n] = ch;
+                n += 1;
+                print!("{}", ch as char);
+            }
+        }
+    }
+    n
+}
+
+// emergency_print! and emergency_println! write to the console from
+// panics and faults. They never wait forever on the console lock, so
+// they work even if the faulting core was in the middle of a print.
+#[macro_export]
+macro_rules! emergency_print {
+    ($($arg:tt)*) => ($crate::console::_emergency_print(format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! emergency_println {
+    () => ($crate::emergency_print!("\n"));
+    ($($arg:tt)*) => ($crate::emergency_print!("core {}: {}\n", cpu::core_id(), format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => ($crate::console::_print(format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! println {
+    () => ($crate::print!("\n"));
+    ($($arg:tt)*) => ($crate::print!("core {}: {}\n", cpu::core_id(), format_args!($($arg)*)));
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    use core::fmt::Write;
+    WRITER.lock().write_fmt(args).unwrap();
+}
+
+// STEAL_SPINS is how long emergency output waits for WRITER before
+// deciding its holder is never going to let it go.
+const STEAL_SPINS: usize = 1_000_000;
+
+// lock_or_steal locks WRITER, forcibly unlocking it if it stays held.
+// The holder may be this core, interrupted by the fault mid-print, or
+// another core that has already died.
+fn lock_or_steal() -> MutexGuard<'static, Writer> {
+    loop {
+        for _ in 0..STEAL_SPINS {
+            if let Some(writer) = WRITER.try_lock() {
+                return writer;
+            }
+            core::hint::spin_loop();
+        }
+        unsafe { WRITER.force_unlock() };
+    }
+}
+
+// EmergencyWriter writes directly to a console device by polling.
+struct EmergencyWriter(&'static dyn Console);
+
+impl fmt::Write for EmergencyWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for ch in s.bytes() {
+            self.0.emergency_write_byte(ch);
+        }
+        Ok(())
+    }
+}
+
+#[doc(hidden)]
+pub fn _emergency_print(args: fmt::Arguments) {
+    use core::fmt::Write;
+    cpu::mask_irqs();
+    let mut writer = lock_or_steal();
+    writer.initialized = true;
+    writer.console.recover();
+    let _ = EmergencyWriter(writer.console).write_fmt(args);
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cmdline_picks_the_last_console() {
+        assert_eq!(cmdline_backend("kernel"), None);
+        assert_eq!(
+            cmdline_backend("kernel console=pl011"),
+            Some(Backend::Pl011)
+        );
+        assert_eq!(
+            cmdline_backend("console=pl011 quiet console=aux"),
+            Some(Backend::Aux)
+        );
+        assert_eq!(cmdline_backend("xconsole=pl011"), None);
+    }
+}
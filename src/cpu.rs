@@ -2,64 +2,100 @@
  * CPU register access.
  */
 
-use crate::define_bit;
-use crate::reg::Reg;
+use crate::reg::{Reg, RegWrite};
+use crate::reg_fields;
+#[cfg(target_os = "none")]
 use core::arch::asm;
+use core::fmt;
 
 #[macro_export]
 macro_rules! msr_imm {
     ($reg:ident, $imm:expr) => {
+        #[cfg(target_os = "none")]
         unsafe {
-            asm!(core::concat!("msr ", stringify!($reg), ", ", stringify!($imm)));
+            asm!(core::concat!(
+                "msr ",
+                stringify!($reg),
+                ", ",
+                stringify!($imm)
+            ));
         }
     };
 }
 
+// cpu_reg64! declares a 64-bit system register, accessed with mrs
+// and msr. An optional trailing rw, ro or wo gives its access mode,
+// like mmio_reg32!.
 #[macro_export]
 macro_rules! cpu_reg64 {
-    ($struct_name:ident, $reg:ident) => {
-        pub struct $struct_name {
-            cached: u64,
-        }
-
+    (@read $struct_name:ident, $reg:ident) => {
         impl $struct_name {
-            // new creates a new instances with preset cached value.
-            #[allow(dead_code)]
-            pub fn new(value: u64) -> Self {
-                $struct_name { cached: value }
-            }
-
-            // new creates a new instances with zeroed cached value.
+            // new creates a new instances with fetched value.
             #[allow(dead_code)]
-            pub fn zero() -> Self {
-                Self::new(0)
+            pub fn fetch() -> Self {
+                let mut x = Self::zero();
+                $crate::reg::RegRead::fetch(&mut x);
+                x
             }
 
-            // new creates a new instances with fetched value.
+            // wait_until creates a new instance once f is true of it.
             #[allow(dead_code)]
-            pub fn fetch() -> Self {
+            pub fn wait_until(f: impl Fn(&Self) -> bool) -> Self {
                 let mut x = Self::zero();
-                x.fetch();
+                $crate::reg::RegRead::wait_until(&mut x, f);
                 x
             }
         }
 
-        impl Reg<u64> for $struct_name {
+        impl $crate::reg::RegRead for $struct_name {
+            fn fetch(&mut self) -> &mut Self {
+                #[cfg(target_os = "none")]
+                let val: u64 = unsafe {
+                    let val;
+                    asm!(core::concat!("mrs {}, ", stringify!($reg)), out(reg) val);
+                    val
+                };
+                #[cfg(not(target_os = "none"))]
+                let val = $crate::mock::read($crate::mock::Loc::Sys(stringify!($reg)));
+                self.cached = val;
+                self
+            }
+        }
+    };
+    (@write $struct_name:ident, $reg:ident) => {
+        impl $crate::reg::RegWrite for $struct_name {
             fn store(&self) {
                 let val = self.cached;
+                #[cfg(target_os = "none")]
                 unsafe {
                     asm!(core::concat!("msr ", stringify!($reg), ", {}"), in(reg) val);
                 }
+                #[cfg(not(target_os = "none"))]
+                $crate::mock::write($crate::mock::Loc::Sys(stringify!($reg)), val);
+            }
+        }
+    };
+    (@base $struct_name:ident) => {
+        pub struct $struct_name {
+            cached: u64,
+        }
+
+        impl $struct_name {
+            // new creates a new instances with preset cached value.
+            #[allow(dead_code)]
+            pub fn new(value: u64) -> Self {
+                $struct_name { cached: value }
             }
 
-            fn fetch(&mut self) -> &mut Self {
-                let val: u64;
-                unsafe {
-                    asm!(core::concat!("mrs {}, ", stringify!($reg)), out(reg) val);
-                }
-                self.cached = val;
-                self
+            // new creates a new instances with zeroed cached value.
+            #[allow(dead_code)]
+            pub fn zero() -> Self {
+                Self::new(0)
             }
+        }
+
+        impl Reg for $struct_name {
+            type Value = u64;
 
             fn get_value(&self) -> u64 {
                 self.cached
@@ -71,26 +107,92 @@ macro_rules! cpu_reg64 {
             }
         }
     };
+    ($struct_name:ident, $reg:ident) => {
+        $crate::cpu_reg64!($struct_name, $reg, rw);
+    };
+    ($struct_name:ident, $reg:ident, rw) => {
+        $crate::cpu_reg64!(@base $struct_name);
+        $crate::cpu_reg64!(@read $struct_name, $reg);
+        $crate::cpu_reg64!(@write $struct_name, $reg);
+    };
+    ($struct_name:ident, $reg:ident, ro) => {
+        $crate::cpu_reg64!(@base $struct_name);
+        $crate::cpu_reg64!(@read $struct_name, $reg);
+    };
+    ($struct_name:ident, $reg:ident, wo) => {
+        $crate::cpu_reg64!(@base $struct_name);
+        $crate::cpu_reg64!(@write $struct_name, $reg);
+    };
 }
 
-cpu_reg64!(CurrentEl, CurrentEl);
+cpu_reg64!(CntfrqEl0, CNTFRQ_EL0);
+cpu_reg64!(CntpctEl0, CNTPCT_EL0, ro);
+cpu_reg64!(CntpCtlEl0, CNTP_CTL_EL0);
+cpu_reg64!(CntpTvalEl0, CNTP_TVAL_EL0);
+cpu_reg64!(CurrentEl, CurrentEl, ro);
+cpu_reg64!(Daif, DAIF);
+cpu_reg64!(EsrEl1, ESR_EL1);
+cpu_reg64!(EsrEl2, ESR_EL2);
 cpu_reg64!(EsrEl3, ESR_EL3);
+cpu_reg64!(ElrEl1, ELR_EL1);
+cpu_reg64!(ElrEl2, ELR_EL2);
 cpu_reg64!(ElrEl3, ELR_EL3);
+cpu_reg64!(FarEl1, FAR_EL1);
+cpu_reg64!(FarEl2, FAR_EL2);
 cpu_reg64!(FarEl3, FAR_EL3);
-cpu_reg64!(MpidrEl1, MPIDR_EL1);
+cpu_reg64!(HcrEl2, HCR_EL2);
+cpu_reg64!(MpidrEl1, MPIDR_EL1, ro);
 cpu_reg64!(ScrEl3, SCR_EL3);
 cpu_reg64!(SpSel, SPSel);
+cpu_reg64!(VBarEl1, VBAR_EL1);
+cpu_reg64!(VBarEl2, VBAR_EL2);
 cpu_reg64!(VBarEl3, VBAR_EL3);
 
-impl ScrEl3 {
-    define_bit!(1, set_irq, get_irq);
-    define_bit!(2, set_fiq, get_fiq);
-    define_bit!(3, set_ea, get_ea);
-    define_bit!(10, set_rw, get_rw);
+reg_fields!(ScrEl3 {
+    irq: rw @ 1,
+    fiq: rw @ 2,
+    ea: rw @ 3,
+    rw: rw @ 10,
+});
+
+reg_fields!(HcrEl2 {
+    fmo: rw @ 3,
+    imo: rw @ 4,
+    amo: rw @ 5,
+    rw: rw @ 31,
+});
+
+reg_fields!(CntpCtlEl0 {
+    enable: rw @ 0,
+    imask: rw @ 1,
+    istatus: ro @ 2,
+});
+
+reg_fields!(Daif {
+    irq_masked: ro @ 7,
+});
+
+reg_fields!(SpSel {
+    sp: rw @ 0,
+});
+
+// irqs_masked is true if IRQs are masked on this core.
+pub fn irqs_masked() -> bool {
+    Daif::fetch().irq_masked()
+}
+
+// mask_irqs masks IRQs on this core.
+pub fn mask_irqs() {
+    msr_imm!(DAIFSet, 0b0010);
 }
 
-impl SpSel {
-    define_bit!(0, set_sp, get_sp);
+// without_irqs runs f with IRQs masked on this core.
+pub fn without_irqs<R>(f: impl FnOnce() -> R) -> R {
+    let daif = Daif::fetch();
+    mask_irqs();
+    let ret = f();
+    daif.store();
+    ret
 }
 
 pub fn current_el() -> u64 {
@@ -100,3 +202,285 @@ pub fn current_el() -> u64 {
 pub fn core_id() -> u64 {
     return MpidrEl1::fetch().get_value() & 0xff;
 }
+
+// esr returns the exception syndrome register for the current EL.
+pub fn esr() -> u64 {
+    match current_el() {
+        3 => EsrEl3::fetch().get_value(),
+        2 => EsrEl2::fetch().get_value(),
+        _ => EsrEl1::fetch().get_value(),
+    }
+}
+
+// elr returns the exception link register for the current EL.
+#[allow(dead_code)]
+pub fn elr() -> u64 {
+    match current_el() {
+        3 => ElrEl3::fetch().get_value(),
+        2 => ElrEl2::fetch().get_value(),
+        _ => ElrEl1::fetch().get_value(),
+    }
+}
+
+// far returns the fault address register for the current EL.
+pub fn far() -> u64 {
+    match current_el() {
+        3 => FarEl3::fetch().get_value(),
+        2 => FarEl2::fetch().get_value(),
+        _ => FarEl1::fetch().get_value(),
+    }
+}
+
+// set_vbar installs a vector table for the current EL.
+pub fn set_vbar(vbar: u64) {
+    match current_el() {
+        3 => VBarEl3::new(vbar).store(),
+        2 => VBarEl2::new(vbar).store(),
+        _ => VBarEl1::new(vbar).store(),
+    }
+}
+
+// ExceptionClass is the EC field of ESR_ELx, the reason for a synchronous exception.
+// Classes with a "lower" flag distinguish exceptions taken from a lower EL.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExceptionClass {
+    Unknown,
+    Wfx,
+    FpTrap,
+    IllegalState,
+    Svc,
+    Hvc,
+    Smc,
+    SysReg,
+    InstrAbort { lower: bool },
+    PcAlignment,
+    DataAbort { lower: bool },
+    SpAlignment,
+    FpException,
+    SError,
+    Breakpoint { lower: bool },
+    Step { lower: bool },
+    Watchpoint { lower: bool },
+    Brk,
+    Other(u8),
+}
+
+impl ExceptionClass {
+    pub fn from_ec(ec: u8) -> Self {
+        match ec {
+            0x00 => Self::Unknown,
+            0x01 => Self::Wfx,
+            0x07 => Self::FpTrap,
+            0x0e => Self::IllegalState,
+            0x11 | 0x15 => Self::Svc,
+            0x12 | 0x16 => Self::Hvc,
+            0x13 | 0x17 => Self::Smc,
+            0x18 => Self::SysReg,
+            0x20 | 0x21 => Self::InstrAbort { lower: ec == 0x20 },
+            0x22 => Self::PcAlignment,
+            0x24 | 0x25 => Self::DataAbort { lower: ec == 0x24 },
+            0x26 => Self::SpAlignment,
+            0x28 | 0x2c => Self::FpException,
+            0x2f => Self::SError,
+            0x30 | 0x31 => Self::Breakpoint { lower: ec == 0x30 },
+            0x32 | 0x33 => Self::Step { lower: ec == 0x32 },
+            0x34 | 0x35 => Self::Watchpoint { lower: ec == 0x34 },
+            0x38 | 0x3c => Self::Brk,
+            _ => Self::Other(ec),
+        }
+    }
+
+    // has_far is true if FAR_ELx holds the faulting address for this class.
+    pub fn has_far(&self) -> bool {
+        matches!(
+            self,
+            Self::InstrAbort { .. }
+                | Self::DataAbort { .. }
+                | Self::PcAlignment
+                | Self::Watchpoint { .. }
+        )
+    }
+}
+
+impl fmt::Display for ExceptionClass {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Self::Unknown => "unknown exception",
+            Self::Wfx => "wfi/wfe trap",
+            Self::FpTrap => "fp/simd access trap",
+            Self::IllegalState => "illegal execution state",
+            Self::Svc => "svc",
+            Self::Hvc => "hvc",
+            Self::Smc => "smc",
+            Self::SysReg => "system register trap",
+            Self::InstrAbort { .. } => "instruction abort",
+            Self::PcAlignment => "pc alignment fault",
+            Self::DataAbort { .. } => "data abort",
+            Self::SpAlignment => "sp alignment fault",
+            Self::FpException => "fp exception",
+            Self::SError => "serror",
+            Self::Breakpoint { .. } => "breakpoint",
+            Self::Step { .. } => "software step",
+            Self::Watchpoint { .. } => "watchpoint",
+            Self::Brk => "brk",
+            Self::Other(ec) => return write!(f, "exception class {:#x}", ec),
+        };
+        f.write_str(name)?;
+        match self {
+            Self::InstrAbort { lower: true }
+            | Self::DataAbort { lower: true }
+            | Self::Breakpoint { lower: true }
+            | Self::Step { lower: true }
+            | Self::Watchpoint { lower: true } => f.write_str(" from lower EL"),
+            _ => Ok(()),
+        }
+    }
+}
+
+// FaultStatus is the DFSC/IFSC field of an abort syndrome.
+// Levels are translation table levels.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FaultStatus {
+    AddressSize(u8),
+    Translation(u8),
+    AccessFlag(u8),
+    Permission(u8),
+    SyncExternal,
+    SyncExternalWalk(u8),
+    Parity,
+    Alignment,
+    TlbConflict,
+    Other(u8),
+}
+
+impl FaultStatus {
+    pub fn from_fsc(fsc: u8) -> Self {
+        let level = fsc & 0b11;
+        match fsc >> 2 {
+            0b0000 => Self::AddressSize(level),
+            0b0001 => Self::Translation(level),
+            0b0010 => Self::AccessFlag(level),
+            0b0011 => Self::Permission(level),
+            0b0101 => Self::SyncExternalWalk(level),
+            _ => match fsc {
+                0b010000 => Self::SyncExternal,
+                0b011000 => Self::Parity,
+                0b100001 => Self::Alignment,
+                0b110000 => Self::TlbConflict,
+                _ => Self::Other(fsc),
+            },
+        }
+    }
+}
+
+impl fmt::Display for FaultStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::AddressSize(l) => write!(f, "address size fault level {}", l),
+            Self::Translation(l) => write!(f, "translation fault level {}", l),
+            Self::AccessFlag(l) => write!(f, "access flag fault level {}", l),
+            Self::Permission(l) => write!(f, "permission fault level {}", l),
+            Self::SyncExternal => write!(f, "synchronous external abort"),
+            Self::SyncExternalWalk(l) => {
+                write!(f, "synchronous external abort on table walk level {}", l)
+            }
+            Self::Parity => write!(f, "parity/ecc error"),
+            Self::Alignment => write!(f, "alignment fault"),
+            Self::TlbConflict => write!(f, "tlb conflict"),
+            Self::Other(fsc) => write!(f, "fault status {:#x}", fsc),
+        }
+    }
+}
+
+// Esr is a decoded exception syndrome, along with the fault address.
+#[derive(Clone, Copy, Debug)]
+pub struct Esr {
+    pub esr: u64,
+    pub far: u64,
+}
+
+#[allow(dead_code)]
+impl Esr {
+    pub fn new(esr: u64, far: u64) -> Self {
+        Esr { esr, far }
+    }
+
+    // fetch reads the syndrome of the last exception taken to the current EL.
+    pub fn fetch() -> Self {
+        Self::new(esr(), far())
+    }
+
+    pub fn class(&self) -> ExceptionClass {
+        ExceptionClass::from_ec(((self.esr >> 26) & 0x3f) as u8)
+    }
+
+    // il is true if the trapped instruction was 32 bits.
+    pub fn il(&self) -> bool {
+        self.esr & (1 << 25) != 0
+    }
+
+    pub fn iss(&self) -> u64 {
+        self.esr & 0x1ff_ffff
+    }
+
+    // fault_status decodes the DFSC/IFSC of data and instruction aborts.
+    pub fn fault_status(&self) -> Option<FaultStatus> {
+        match self.class() {
+            ExceptionClass::DataAbort { .. } | ExceptionClass::InstrAbort { .. } => {
+                Some(FaultStatus::from_fsc((self.iss() & 0x3f) as u8))
+            }
+            _ => None,
+        }
+    }
+
+    // isv is true if the sas, srt (and sse, sf, ar) fields of a data abort are valid.
+    pub fn isv(&self) -> bool {
+        self.iss() & (1 << 24) != 0
+    }
+
+    // sas is the access size of a data abort, in bytes.
+    pub fn sas(&self) -> u64 {
+        1 << ((self.iss() >> 22) & 0b11)
+    }
+
+    // srt is the register transferred by a data abort.
+    pub fn srt(&self) -> u64 {
+        (self.iss() >> 16) & 0x1f
+    }
+
+    // wnr is true if a data abort was caused by a write.
+    pub fn wnr(&self) -> bool {
+        self.iss() & (1 << 6) != 0
+    }
+
+    // imm16 is the immediate of an svc, hvc, smc or brk.
+    pub fn imm16(&self) -> u64 {
+        self.iss() & 0xffff
+    }
+}
+
+impl fmt::Display for Esr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let class = self.class();
+        write!(f, "{}", class)?;
+        match class {
+            ExceptionClass::DataAbort { .. } => {
+                write!(f, ": {}", self.fault_status().unwrap())?;
+                write!(f, ", {}", if self.wnr() { "write" } else { "read" })?;
+                if self.isv() {
+                    write!(f, ", x{}", self.srt())?;
+                }
+            }
+            ExceptionClass::InstrAbort { .. } => write!(f, ": {}", self.fault_status().unwrap())?,
+            ExceptionClass::Svc
+            | ExceptionClass::Hvc
+            | ExceptionClass::Smc
+            | ExceptionClass::Brk => write!(f, " #{:#x}", self.imm16())?,
+            _ => write!(f, ", iss {:#x}", self.iss())?,
+        }
+        if class.has_far() {
+            write!(f, ", addr {:#x}", self.far)?;
+        }
+        Ok(())
+    }
+}
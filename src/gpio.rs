@@ -3,33 +3,52 @@
  * BCM2837 GPIO support.
  */
 
-use crate::define_bits;
-use crate::mmio::Reg32Array;
-use crate::reg::Reg;
-use crate::{asm, board, mmio_reg32, mmio_reg32_array};
+use crate::mmio::RegArray;
+use crate::reg::{Reg, RegWrite};
+use crate::{asm, board, field_enum, mmio_reg32, mmio_reg32_array, reg_fields};
 
 const GPIO_MAXPIN: u32 = 53;
-const ALT5: u32 = 2;
+
+field_enum!(
+    // PinFunction is what a pin is used for, in GPFSELn.
+    pub enum PinFunction {
+        Input = 0,
+        Output = 1,
+        Alt0 = 4,
+        Alt1 = 5,
+        Alt2 = 6,
+        Alt3 = 7,
+        Alt4 = 3,
+        Alt5 = 2,
+    }
+);
+
+field_enum!(
+    // Pull is a pin's pull-up/down control, in GPPUD.
+    pub enum Pull {
+        Off = 0,
+        Down = 1,
+        Up = 2,
+    }
+);
 
 mmio_reg32_array!(GpFSel, 6, board::GPIO_BASE);
 mmio_reg32!(GpPud, board::GPIO_BASE + 0x94);
 mmio_reg32_array!(GpPupdClk, 2, board::GPIO_BASE + 0x98);
 
 impl GpFSel {
-    fn store_pin_function(self, pin: u32, val: u32) {
-        _bitvec_write(self, 3, pin, val);
+    fn store_pin_function(self, pin: u32, function: PinFunction) {
+        _bitvec_write(&self, 3, pin, function.into());
     }
 }
 
-impl GpPud {
-    const PUD_DISABLED: u32 = 0;
-
-    define_bits!(0, 2, u32, set_pud, get_pud);
-}
+reg_fields!(GpPud {
+    pud: rw @ 0..2 as Pull,
+});
 
 impl GpPupdClk {
     fn store_pin_clk(self, pin: u32, val: u32) {
-        _bitvec_write(self, 1, pin, val);
+        _bitvec_write(&self, 1, pin, val);
     }
 }
 
@@ -38,23 +57,25 @@ impl GpPupdClk {
 // starting iwth the lowest bits.  Elements never span a register, and the
 // upper bits of the register are left unused if the element size doesnt equally
 // divide 32.
-fn _bitvec_write<T: Reg32Array>(reg_vec: T, sz: u8, pin: u32, val: u32) {
+fn _bitvec_write<const N: usize>(reg_vec: &RegArray<u32, N>, sz: u8, pin: u32, val: u32) {
     if pin >= GPIO_MAXPIN {
         panic!("pin {} is too large", pin);
     }
 
     let fields_per_u32 = 32 / (sz as u32);
     let reg_index = (pin / fields_per_u32) as usize;
-    let reg_offset = (pin % fields_per_u32) as u8;
+    let reg_shift = ((pin % fields_per_u32) * sz as u32) as u8;
 
-    reg_vec.index_fetch(reg_index).set_bits(reg_offset, sz, val).store();
+    reg_vec
+        .index(reg_index)
+        .modify(|r| r.set_bits(reg_shift, sz, val));
 }
 
 // pin_disable_pull sets the pullup behavior of a GPIO pin to disable.
 fn pin_disable_pull(pin: u32) {
     // See BCM2837 ARM Peripherals pg 101.
     // Write intended value
-    GpPud::zero().set_pud(GpPud::PUD_DISABLED).store();
+    GpPud::zero().write(|w| w.set_pud(Pull::Off));
 
     // wait
     asm::delay(150);
@@ -73,5 +94,74 @@ fn pin_disable_pull(pin: u32) {
 // pin_use_as_alt5 sets a GPIO pin to an ALT5 alternative function.
 pub fn pin_use_as_alt5(pin: u32) {
     pin_disable_pull(pin);
-    GpFSel::new().store_pin_function(pin, ALT5); // ALT5
+    GpFSel::new().store_pin_function(pin, PinFunction::Alt5);
+}
+
+// pin_use_as_alt0 sets a GPIO pin to an ALT0 alternative function.
+pub fn pin_use_as_alt0(pin: u32) {
+    pin_disable_pull(pin);
+    GpFSel::new().store_pin_function(pin, PinFunction::Alt0);
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+    use crate::mock::{self, Loc};
+
+    const GPFSEL0: usize = board::GPIO_BASE;
+    const GPPUD: usize = board::GPIO_BASE + 0x94;
+    const GPPUDCLK0: usize = board::GPIO_BASE + 0x98;
+
+    fn reg(addr: usize) -> u64 {
+        mock::value(Loc::Mmio(addr))
+    }
+
+    #[test]
+    fn bitvec_write_packs_3bit_fields() {
+        mock::reset();
+        mock::preset(Loc::Mmio(GPFSEL0 + 4), 0x3fff_ffff);
+        GpFSel::new().store_pin_function(9, PinFunction::Alt5); // GPFSEL0 bits 27..30
+        GpFSel::new().store_pin_function(14, PinFunction::Alt5); // GPFSEL1 bits 12..15
+        GpFSel::new().store_pin_function(15, PinFunction::Alt0); // GPFSEL1 bits 15..18
+        assert_eq!(reg(GPFSEL0), 2 << 27);
+        assert_eq!(
+            reg(GPFSEL0 + 4),
+            0x3fff_ffff & !(0o77 << 12) | 2 << 12 | 4 << 15
+        );
+    }
+
+    #[test]
+    fn bitvec_write_packs_1bit_fields() {
+        mock::reset();
+        GpPupdClk::new().store_pin_clk(31, 1);
+        GpPupdClk::new().store_pin_clk(33, 1);
+        assert_eq!(reg(GPPUDCLK0), 1 << 31);
+        assert_eq!(reg(GPPUDCLK0 + 4), 1 << 1);
+    }
+
+    #[test]
+    #[should_panic]
+    fn bitvec_write_rejects_bad_pins() {
+        GpFSel::new().store_pin_function(54, PinFunction::Alt0);
+    }
+
+    #[test]
+    fn pull_is_disabled_before_selecting_function() {
+        mock::reset();
+        pin_use_as_alt5(14);
+        let writes: Vec<(usize, u64)> = mock::writes(board::GPIO_BASE, 0x100)
+            .into_iter()
+            .map(|(off, val)| (off + board::GPIO_BASE, val))
+            .collect();
+        assert_eq!(
+            writes,
+            [
+                (GPPUD, 0),
+                (GPPUDCLK0, 1 << 14),
+                (GPPUD, 0),
+                (GPPUDCLK0, 0),
+                (GPFSEL0 + 4, 2 << 12),
+            ]
+        );
+    }
 }
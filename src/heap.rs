@@ -0,0 +1,249 @@
+/*
+ * heap.rs
+ * Kernel heap.
+ *
+ * A first-fit linked-list allocator over [HEAP_BASE, HEAP_TOP).
+ * Free blocks are kept in a singly linked list sorted by address,
+ * with the list node stored in the free memory itself. Adjacent free
+ * blocks are merged when memory is returned.
+ */
+
+use crate::{cpu, println};
+use core::alloc::{GlobalAlloc, Layout};
+use core::mem::size_of;
+use core::ptr;
+use spin::Mutex;
+
+// Every block (free or allocated) is a multiple of BLOCK_ALIGN in size
+// and starts on a BLOCK_ALIGN boundary, so a free block can always
+// hold a FreeBlock header and splitting never leaves an unusable sliver.
+const BLOCK_ALIGN: usize = 16;
+
+struct FreeBlock {
+    size: usize,
+    next: *mut FreeBlock,
+}
+
+// HeapStats is a snapshot of the heap's bookkeeping.
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug)]
+pub struct HeapStats {
+    pub size: usize,
+    pub in_use: usize,
+    pub peak: usize,
+    pub free_blocks: usize,
+    pub free_bytes: usize,
+}
+
+struct Heap {
+    head: *mut FreeBlock,
+    size: usize,
+    in_use: usize,
+    peak: usize,
+}
+
+// The raw pointers are only ever touched while holding the heap lock.
+unsafe impl Send for Heap {}
+
+pub struct LockedHeap(Mutex<Heap>);
+
+#[cfg_attr(target_os = "none", global_allocator)]
+static HEAP: LockedHeap = LockedHeap(Mutex::new(Heap::empty()));
+
+fn align_up(addr: usize, align: usize) -> usize {
+    (addr + align - 1) & !(align - 1)
+}
+
+fn align_down(addr: usize, align: usize) -> usize {
+    addr & !(align - 1)
+}
+
+// block_layout returns the size and alignment actually used for a request.
+fn block_layout(layout: Layout) -> (usize, usize) {
+    let size = align_up(layout.size().max(size_of::<FreeBlock>()), BLOCK_ALIGN);
+    let align = layout.align().max(BLOCK_ALIGN);
+    (size, align)
+}
+
+impl Heap {
+    const fn empty() -> Self {
+        Heap {
+            head: ptr::null_mut(),
+            size: 0,
+            in_use: 0,
+            peak: 0,
+        }
+    }
+
+    // add_region hands the memory in [base, top) to the heap.
+    unsafe fn add_region(&mut self, base: usize, top: usize) {
+        let base = align_up(base, BLOCK_ALIGN);
+        let top = align_down(top, BLOCK_ALIGN);
+        if top <= base {
+            return;
+        }
+        self.size += top - base;
+        self.free(base, top - base);
+    }
+
+    // alloc finds the first free block that can hold size bytes at align,
+    // splitting off any unused memory before and after the allocation.
+    unsafe fn alloc(&mut self, size: usize, align: usize) -> *mut u8 {
+        let mut prev: *mut FreeBlock = ptr::null_mut();
+        let mut cur = self.head;
+        while !cur.is_null() {
+            let block_start = cur as usize;
+            let block_end = block_start + (*cur).size;
+
+            let start = align_up(block_start, align);
+            let end = start + size;
+            if end <= block_end {
+                let next = (*cur).next;
+
+                // Unlink the block, then give back the pieces we don't use.
+                // Since everything is BLOCK_ALIGN'd both pieces are either
+                // empty or big enough to be a free block.
+                if prev.is_null() {
+                    self.head = next;
+                } else {
+                    (*prev).next = next;
+                }
+                if end < block_end {
+                    self.free(end, block_end - end);
+                }
+                if start > block_start {
+                    self.free(block_start, start - block_start);
+                }
+
+                self.in_use += size;
+                self.peak = self.peak.max(self.in_use);
+                return start as *mut u8;
+            }
+            prev = cur;
+            cur = (*cur).next;
+        }
+        ptr::null_mut()
+    }
+
+    // free inserts [addr, addr+size) into the address ordered free list,
+    // merging it with its neighbors when they are adjacent.
+    unsafe fn free(&mut self, addr: usize, size: usize) {
+        let mut prev: *mut FreeBlock = ptr::null_mut();
+        let mut next = self.head;
+        while !next.is_null() && (next as usize) < addr {
+            prev = next;
+            next = (*next).next;
+        }
+
+        let block = addr as *mut FreeBlock;
+        block.write(FreeBlock { size, next });
+        if !next.is_null() && addr + size == next as usize {
+            (*block).size += (*next).size;
+            (*block).next = (*next).next;
+        }
+
+        if prev.is_null() {
+            self.head = block;
+        } else if prev as usize + (*prev).size == addr {
+            (*prev).size += (*block).size;
+            (*prev).next = (*block).next;
+        } else {
+            (*prev).next = block;
+        }
+    }
+
+    fn stats(&self) -> HeapStats {
+        let mut free_blocks = 0;
+        let mut free_bytes = 0;
+        let mut cur = self.head;
+        while !cur.is_null() {
+            unsafe {
+                free_blocks += 1;
+                free_bytes += (*cur).size;
+                cur = (*cur).next;
+            }
+        }
+        HeapStats {
+            size: self.size,
+            in_use: self.in_use,
+            peak: self.peak,
+            free_blocks,
+            free_bytes,
+        }
+    }
+}
+
+unsafe impl GlobalAlloc for LockedHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let (size, align) = block_layout(layout);
+        self.0.lock().alloc(size, align)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let (size, _) = block_layout(layout);
+        let mut heap = self.0.lock();
+        heap.in_use -= size;
+        heap.free(ptr as usize, size);
+    }
+}
+
+// init gives the memory in [base, top) to the kernel heap.
+// It must be called once, before anything is allocated.
+pub fn init(base: usize, top: usize) {
+    unsafe { HEAP.0.lock().add_region(base, top) }
+}
+
+// stats returns the current heap usage.
+pub fn stats() -> HeapStats {
+    HEAP.0.lock().stats()
+}
+
+#[cfg(target_os = "none")]
+#[alloc_error_handler]
+fn alloc_error(layout: Layout) -> ! {
+    println!(
+        "out of memory allocating {} bytes (align {})",
+        layout.size(),
+        layout.align()
+    );
+    println!("  {:?}", stats());
+    panic!("allocation failed");
+}
+
+#[cfg(all(test, target_os = "none"))]
+mod tests {
+    use super::stats;
+    use alloc::boxed::Box;
+    use alloc::vec::Vec;
+
+    #[test_case]
+    fn alloc_and_free() {
+        let before = stats().in_use;
+        let b = Box::new([0u8; 100]);
+        assert!(stats().in_use >= before + 100);
+        drop(b);
+        assert_eq!(stats().in_use, before);
+    }
+
+    #[test_case]
+    fn free_blocks_coalesce() {
+        let before = stats();
+        let v: Vec<Box<u64>> = (0..64).map(Box::new).collect();
+        for (i, b) in v.iter().enumerate() {
+            assert_eq!(**b, i as u64);
+        }
+        drop(v);
+        let after = stats();
+        assert_eq!(after.in_use, before.in_use);
+        assert_eq!(after.free_blocks, before.free_blocks);
+    }
+
+    #[test_case]
+    fn aligned_alloc() {
+        #[repr(align(256))]
+        struct Aligned(u8);
+        let b = Box::new(Aligned(7));
+        assert_eq!(&*b as *const Aligned as usize % 256, 0);
+        assert_eq!(b.0, 7);
+    }
+}
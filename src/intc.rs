@@ -0,0 +1,87 @@
+/*
+ * intc.rs
+ * BCM2835 ARM interrupt controller support.
+ * Ref: BCM2837 ARM Peripherals, section 7.
+ */
+
+use crate::reg::{Reg, RegWrite};
+use crate::{board, mmio_reg32, mmio_reg32_array};
+
+// Basic IRQs 0..8 are ARM specific (ARM timer, mailbox, doorbells, ...).
+// GPU IRQs 0..64 are split across two banks of 32.
+pub const NUM_BASIC: u32 = 8;
+pub const NUM_GPU: u32 = 64;
+
+mmio_reg32!(IrqBasicPending, board::INTC_BASE + 0x200, ro);
+mmio_reg32_array!(IrqPending, 2, board::INTC_BASE + 0x204, ro);
+mmio_reg32_array!(IrqEnable, 2, board::INTC_BASE + 0x210);
+mmio_reg32!(IrqEnableBasic, board::INTC_BASE + 0x218);
+mmio_reg32_array!(IrqDisable, 2, board::INTC_BASE + 0x21c);
+mmio_reg32!(IrqDisableBasic, board::INTC_BASE + 0x224);
+
+impl IrqBasicPending {
+    // Bits 8 and 9 flag pending GPU IRQs in bank 0 and 1, and bits
+    // 10..21 are shortcuts for a few popular GPU IRQs.
+    const GPU_PENDING: u32 = !0xff;
+
+    fn basic(&self) -> u32 {
+        self.get_bits(0, 8)
+    }
+
+    fn gpu(&self) -> bool {
+        self.get_value() & Self::GPU_PENDING != 0
+    }
+}
+
+// init disables all interrupts.
+pub fn init() {
+    IrqDisableBasic::new(0xff).store();
+    for bank in 0..2 {
+        IrqDisable::new().index(bank).set_value(!0).store();
+    }
+}
+
+// The enable and disable registers only act on bits written as 1.
+
+pub fn enable_basic(irq: u32) {
+    assert!(irq < NUM_BASIC, "basic irq {} is too big", irq);
+    IrqEnableBasic::zero().set_bit(irq as u8, true).store();
+}
+
+#[allow(dead_code)]
+pub fn disable_basic(irq: u32) {
+    assert!(irq < NUM_BASIC, "basic irq {} is too big", irq);
+    IrqDisableBasic::zero().set_bit(irq as u8, true).store();
+}
+
+pub fn enable_gpu(irq: u32) {
+    assert!(irq < NUM_GPU, "gpu irq {} is too big", irq);
+    IrqEnable::new()
+        .index((irq / 32) as usize)
+        .set_bit((irq % 32) as u8, true)
+        .store();
+}
+
+#[allow(dead_code)]
+pub fn disable_gpu(irq: u32) {
+    assert!(irq < NUM_GPU, "gpu irq {} is too big", irq);
+    IrqDisable::new()
+        .index((irq / 32) as usize)
+        .set_bit((irq % 32) as u8, true)
+        .store();
+}
+
+// pending_basic returns a bitmask of the pending basic IRQs.
+pub fn pending_basic() -> u32 {
+    IrqBasicPending::fetch().basic()
+}
+
+// pending_gpu returns a bitmask of the pending GPU IRQs, 0..64.
+pub fn pending_gpu() -> u64 {
+    if !IrqBasicPending::fetch().gpu() {
+        return 0;
+    }
+    let lo = IrqPending::new().index_fetch(0).get_value() as u64;
+    let hi = IrqPending::new().index_fetch(1).get_value() as u64;
+    hi << 32 | lo
+}
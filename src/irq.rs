@@ -0,0 +1,115 @@
+/*
+ * irq.rs
+ * IRQ routing and dispatch.
+ *
+ * Every IRQ is taken through the BCM2836 local controller of the core
+ * it is routed to. Local sources (core timers, mailboxes) are per-core,
+ * and the GPU source fans out to the BCM2835 controller's basic and
+ * GPU IRQs. All GPU IRQs are routed to core 0.
+ */
+
+// Nothing routes or takes interrupts in the host build.
+#![cfg_attr(not(target_os = "none"), allow(dead_code))]
+
+use crate::{cpu, intc, local_intc};
+use spin::Mutex;
+
+// Source identifies an interrupt.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Source {
+    // BCM2835 basic IRQs, 0..8.
+    Basic(u32),
+    // BCM2835 GPU IRQs, 0..64.
+    Gpu(u32),
+    // BCM2836 per-core sources, see local_intc.
+    Local(u32),
+}
+
+#[allow(dead_code)]
+impl Source {
+    pub const CNTPS: Source = Source::Local(local_intc::CNTPS);
+    pub const CNTPNS: Source = Source::Local(local_intc::CNTPNS);
+    pub const CNTV: Source = Source::Local(local_intc::CNTV);
+
+    // index is the slot for this source in HANDLERS.
+    fn index(&self) -> usize {
+        let idx = match *self {
+            Source::Basic(n) if n < intc::NUM_BASIC => n,
+            Source::Gpu(n) if n < intc::NUM_GPU => intc::NUM_BASIC + n,
+            Source::Local(n) if n < local_intc::NUM_SOURCES && n != local_intc::GPU => {
+                intc::NUM_BASIC + intc::NUM_GPU + n
+            }
+            _ => panic!("bad irq source {:?}", self),
+        };
+        idx as usize
+    }
+}
+
+pub type Handler = fn();
+
+const NUM_HANDLERS: usize = (intc::NUM_BASIC + intc::NUM_GPU + local_intc::NUM_SOURCES) as usize;
+static HANDLERS: Mutex<[Option<Handler>; NUM_HANDLERS]> = Mutex::new([None; NUM_HANDLERS]);
+
+// init disables all BCM2835 interrupts and routes them to core 0.
+pub fn init() {
+    intc::init();
+    local_intc::route_gpu(0);
+}
+
+// register installs handler for source and enables it.
+// Local sources are enabled for the calling core only, other
+// cores share the handler but must call enable themselves.
+pub fn register(source: Source, handler: Handler) {
+    // The dispatcher takes the HANDLERS lock, so don't let it interrupt us.
+    cpu::without_irqs(|| HANDLERS.lock()[source.index()] = Some(handler));
+    enable(source);
+}
+
+// enable unmasks source (on this core, for local sources).
+pub fn enable(source: Source) {
+    match source {
+        Source::Basic(n) => intc::enable_basic(n),
+        Source::Gpu(n) => intc::enable_gpu(n),
+        Source::Local(n) => local_intc::enable(cpu::core_id(), n),
+    }
+}
+
+// disable masks source (on this core, for local sources).
+pub fn disable(source: Source) {
+    match source {
+        Source::Basic(n) => intc::disable_basic(n),
+        Source::Gpu(n) => intc::disable_gpu(n),
+        Source::Local(n) => local_intc::disable(cpu::core_id(), n),
+    }
+}
+
+fn call(source: Source) {
+    let handler = HANDLERS.lock()[source.index()];
+    match handler {
+        Some(handler) => handler(),
+        None => panic!("unhandled irq {:?}", source),
+    }
+}
+
+// for_each_bit calls f with the index of each set bit in bits.
+fn for_each_bit(mut bits: u64, mut f: impl FnMut(u32)) {
+    while bits != 0 {
+        let bit = bits.trailing_zeros();
+        bits &= bits - 1;
+        f(bit);
+    }
+}
+
+// dispatch calls the handlers for all pending interrupts on this core.
+// It is called from the IRQ vector.
+pub fn dispatch() {
+    let pending = local_intc::pending(cpu::core_id());
+    for_each_bit(pending as u64, |bit| {
+        if bit == local_intc::GPU {
+            for_each_bit(intc::pending_basic() as u64, |n| call(Source::Basic(n)));
+            for_each_bit(intc::pending_gpu(), |n| call(Source::Gpu(n)));
+        } else {
+            call(Source::Local(bit));
+        }
+    });
+}
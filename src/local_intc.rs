@@ -0,0 +1,90 @@
+/*
+ * local_intc.rs
+ * BCM2836 per-core local interrupt controller support.
+ * Ref: BCM2836 ARM-local peripherals (QA7), section 4.
+ */
+
+use crate::reg::{Reg, RegWrite};
+use crate::{board, mmio_reg32, mmio_reg32_array, reg_fields};
+
+// Local interrupt sources, as bit numbers in the per-core IRQ source register.
+pub const CNTPS: u32 = 0;
+pub const CNTPNS: u32 = 1;
+#[allow(dead_code)]
+pub const CNTHP: u32 = 2;
+pub const CNTV: u32 = 3;
+pub const MAILBOX0: u32 = 4;
+pub const GPU: u32 = 8;
+#[allow(dead_code)]
+pub const PMU: u32 = 9;
+pub const NUM_SOURCES: u32 = 12;
+
+mmio_reg32!(GpuRouting, board::LOCAL_BASE + 0x0c);
+mmio_reg32_array!(CoreTimerIrqCntl, 4, board::LOCAL_BASE + 0x40);
+mmio_reg32_array!(CoreMailboxIrqCntl, 4, board::LOCAL_BASE + 0x50);
+mmio_reg32_array!(CoreIrqSource, 4, board::LOCAL_BASE + 0x60, ro);
+mmio_reg32_array!(CoreMailboxSet, 16, board::LOCAL_BASE + 0x80, wo);
+mmio_reg32_array!(CoreMailboxClr, 16, board::LOCAL_BASE + 0xc0);
+
+reg_fields!(GpuRouting {
+    irq_core: rw @ 0..2,
+    fiq_core: rw @ 2..4,
+});
+
+// route_gpu sends all GPU (BCM2835 intc) IRQs to core.
+pub fn route_gpu(core: u64) {
+    GpuRouting::zero().modify(|r| r.set_irq_core(core as u32));
+}
+
+// enable turns on local source src for core.
+// Only the core timer and mailbox sources can be enabled this way,
+// the GPU source is controlled by route_gpu.
+pub fn enable(core: u64, src: u32) {
+    set_enable(core, src, true);
+}
+
+#[allow(dead_code)]
+pub fn disable(core: u64, src: u32) {
+    set_enable(core, src, false);
+}
+
+fn set_enable(core: u64, src: u32, val: bool) {
+    let core = core as usize;
+    match src {
+        CNTPS..=CNTV => {
+            CoreTimerIrqCntl::new()
+                .index(core)
+                .modify(|r| r.set_bit(src as u8, val));
+        }
+        MAILBOX0..=7 => {
+            let mb = (src - MAILBOX0) as u8;
+            CoreMailboxIrqCntl::new()
+                .index(core)
+                .modify(|r| r.set_bit(mb, val));
+        }
+        _ => panic!("local irq {} can't be enabled", src),
+    }
+}
+
+// pending returns the bitmask of local sources pending for core.
+pub fn pending(core: u64) -> u32 {
+    CoreIrqSource::new().index_fetch(core as usize).get_value()
+}
+
+// mailbox_send sets bits in one of core's four mailboxes.
+#[allow(dead_code)]
+pub fn mailbox_send(core: u64, mb: u32, bits: u32) {
+    CoreMailboxSet::new()
+        .index(core as usize * 4 + mb as usize)
+        .set_value(bits)
+        .store();
+}
+
+// mailbox_take reads and clears one of core's four mailboxes.
+#[allow(dead_code)]
+pub fn mailbox_take(core: u64, mb: u32) -> u32 {
+    let reg = CoreMailboxClr::new().index_fetch(core as usize * 4 + mb as usize);
+    let bits = reg.get_value();
+    reg.store(); // write 1 to clear
+    bits
+}
@@ -0,0 +1,403 @@
+/*
+ * log.rs
+ * Leveled kernel logging.
+ *
+ * error!, warn!, info!, debug! and trace! format a timestamped line,
+ * filter it by level for the calling module, and hand it to every
+ * registered sink. The memory sink is always on and keeps the most
+ * recent lines in a ring that dmesg prints, so messages logged before
+ * the console is usable are not lost.
+ */
+
+use crate::{board, cpu, print, semihosting, time};
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+
+// Level is how important a message is. Lower levels are more important.
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error = 1,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    fn tag(&self) -> char {
+        match self {
+            Level::Error => 'E',
+            Level::Warn => 'W',
+            Level::Info => 'I',
+            Level::Debug => 'D',
+            Level::Trace => 'T',
+        }
+    }
+}
+
+// Sink receives every complete log line, including the trailing newline.
+pub trait Sink: Sync {
+    fn write_line(&self, line: &str);
+}
+
+// ConsoleSink writes to the kernel console.
+pub struct ConsoleSink;
+
+pub static CONSOLE: ConsoleSink = ConsoleSink;
+
+impl Sink for ConsoleSink {
+    fn write_line(&self, line: &str) {
+        print!("{}", line);
+    }
+}
+
+// SemihostingSink writes to the debugger or qemu's stderr.
+// It only works when running with semihosting enabled.
+pub struct SemihostingSink;
+
+#[allow(dead_code)]
+pub static SEMIHOSTING: SemihostingSink = SemihostingSink;
+
+impl Sink for SemihostingSink {
+    fn write_line(&self, line: &str) {
+        for ch in line.bytes() {
+            semihosting::write_c(ch);
+        }
+    }
+}
+
+// MemorySink keeps recent lines in a ring, overwriting the oldest.
+pub struct MemorySink {
+    ring: Mutex<LogRing>,
+    // holder is 1 + the id of the core holding ring, or 0.
+    holder: AtomicU64,
+}
+
+pub static MEMORY: MemorySink = MemorySink {
+    ring: Mutex::new(LogRing::new()),
+    holder: AtomicU64::new(0),
+};
+
+const LOG_RING_SIZE: usize = 16 * 1024;
+
+struct LogRing {
+    buf: [u8; LOG_RING_SIZE],
+    // written counts bytes ever written; it wraps freely.
+    written: usize,
+    // dropped is the last byte overwritten, the one before the oldest.
+    dropped: u8,
+}
+
+impl LogRing {
+    const fn new() -> Self {
+        LogRing {
+            buf: [0; LOG_RING_SIZE],
+            written: 0,
+            dropped: b'\n',
+        }
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            let slot = &mut self.buf[self.written % LOG_RING_SIZE];
+            if self.written >= LOG_RING_SIZE {
+                self.dropped = *slot;
+            }
+            *slot = b;
+            self.written = self.written.wrapping_add(1);
+        }
+    }
+
+    // for_each_line calls f with each complete line still in the ring,
+    // oldest first. A line partly overwritten by newer ones is skipped.
+    fn for_each_line(&self, mut f: impl FnMut(&[u8])) {
+        let len = self.written.min(LOG_RING_SIZE);
+        let start = self.written.wrapping_sub(len);
+        let mut line = [0u8; LINE_SIZE];
+        let mut n = 0;
+        // The oldest line is partial unless the overwrite stopped
+        // right after a newline.
+        let mut partial = self.dropped != b'\n';
+        for i in 0..len {
+            let b = self.buf[start.wrapping_add(i) % LOG_RING_SIZE];
+            if n < LINE_SIZE {
+                line[n] = b;
+                n += 1;
+            }
+            if b == b'\n' {
+                if !partial {
+                    f(&line[..n]);
+                }
+                partial = false;
+                n = 0;
+            }
+        }
+    }
+}
+
+impl MemorySink {
+    // with_ring runs f on the ring with irqs masked. It returns None
+    // without running f if this core already holds the ring, which
+    // happens when printing the ring leads back to logging.
+    fn with_ring<R>(&self, f: impl FnOnce(&mut LogRing) -> R) -> Option<R> {
+        cpu::without_irqs(|| {
+            let me = cpu::core_id() + 1;
+            if self.holder.load(Ordering::Relaxed) == me {
+                return None;
+            }
+            let mut ring = self.ring.lock();
+            self.holder.store(me, Ordering::Relaxed);
+            let ret = f(&mut ring);
+            self.holder.store(0, Ordering::Relaxed);
+            Some(ret)
+        })
+    }
+
+    // for_each_line calls f with each complete line in the ring,
+    // oldest first.
+    pub fn for_each_line(&self, f: impl FnMut(&[u8])) {
+        self.with_ring(|ring| ring.for_each_line(f));
+    }
+}
+
+impl Sink for MemorySink {
+    fn write_line(&self, line: &str) {
+        self.with_ring(|ring| ring.push(line.as_bytes()));
+    }
+}
+
+// dmesg prints the lines held by the memory sink to the console.
+#[allow(dead_code)]
+pub fn dmesg() {
+    MEMORY.for_each_line(|line| {
+        if let Ok(s) = core::str::from_utf8(line) {
+            print!("{}", s);
+        }
+    });
+}
+
+// SINKS are the sinks each line is written to, besides MEMORY.
+const MAX_SINKS: usize = 4;
+static SINKS: Mutex<[Option<&'static dyn Sink>; MAX_SINKS]> =
+    Mutex::new([Some(&CONSOLE), None, None, None]);
+
+// add_sink starts sending log lines to sink.
+#[allow(dead_code)]
+pub fn add_sink(sink: &'static dyn Sink) {
+    cpu::without_irqs(|| {
+        let mut sinks = SINKS.lock();
+        match sinks.iter_mut().find(|s| s.is_none()) {
+            Some(slot) => *slot = Some(sink),
+            None => panic!("too many log sinks"),
+        }
+    });
+}
+
+// remove_sink stops sending log lines to sink.
+#[allow(dead_code)]
+pub fn remove_sink(sink: &'static dyn Sink) {
+    cpu::without_irqs(|| {
+        for slot in SINKS.lock().iter_mut() {
+            if let Some(s) = slot {
+                if core::ptr::eq(*s as *const _ as *const u8, sink as *const _ as *const u8) {
+                    *slot = None;
+                }
+            }
+        }
+    });
+}
+
+// FILTERS overrides board::LOG_LEVEL for modules whose path starts
+// with the given prefix. The longest matching prefix wins.
+const MAX_FILTERS: usize = 16;
+static FILTERS: Mutex<[Option<(&'static str, Level)>; MAX_FILTERS]> =
+    Mutex::new([None; MAX_FILTERS]);
+
+// set_module_level logs messages from modules under prefix
+// (such as "os::uart") at level and above.
+#[allow(dead_code)]
+pub fn set_module_level(prefix: &'static str, level: Level) {
+    cpu::without_irqs(|| {
+        let mut filters = FILTERS.lock();
+        let slot = filters
+            .iter()
+            .position(|f| matches!(f, Some((p, _)) if *p == prefix))
+            .or_else(|| filters.iter().position(|f| f.is_none()));
+        match slot {
+            Some(i) => filters[i] = Some((prefix, level)),
+            None => panic!("too many log filters"),
+        }
+    });
+}
+
+// enabled is true if messages at level from module should be logged.
+fn enabled(level: Level, module: &str) -> bool {
+    let mut max = board::LOG_LEVEL;
+    let mut best = 0;
+    for (prefix, l) in FILTERS.lock().iter().flatten() {
+        if prefix.len() >= best && module.starts_with(prefix) {
+            best = prefix.len();
+            max = *l;
+        }
+    }
+    level <= max
+}
+
+// LINE_SIZE is the longest line logged. Longer messages are truncated.
+const LINE_SIZE: usize = 256;
+
+struct LineBuf {
+    buf: [u8; LINE_SIZE],
+    len: usize,
+}
+
+impl LineBuf {
+    fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.buf[..self.len]).unwrap_or("")
+    }
+}
+
+impl fmt::Write for LineBuf {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        // Always leave room for the newline, and only cut s between
+        // characters.
+        let mut n = s.len().min(LINE_SIZE - 1 - self.len);
+        while !s.is_char_boundary(n) {
+            n -= 1;
+        }
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $($arg:tt)*) => ($crate::log::_log($level, module_path!(), format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Error, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Warn, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Info, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Debug, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Trace, $($arg)*));
+}
+
+#[doc(hidden)]
+pub fn _log(level: Level, module: &str, args: fmt::Arguments) {
+    if !cpu::without_irqs(|| enabled(level, module)) {
+        return;
+    }
+    let line = format_line(level, module, args);
+    let line = line.as_str();
+
+    // The locks are only held with irqs masked, so a handler on this
+    // core can't log while we hold them. The sinks themselves run with
+    // irqs as they were, so console output can still be buffered.
+    let sinks = cpu::without_irqs(|| {
+        MEMORY.write_line(line);
+        *SINKS.lock()
+    });
+    for sink in sinks.iter().flatten() {
+        sink.write_line(line);
+    }
+}
+
+// log_to_ring logs a message to the memory sink only, whatever the
+// level filters say. It is for tracing things the other sinks would
+// trip over, like the console's own registers.
+#[allow(dead_code)]
+pub fn log_to_ring(level: Level, module: &str, args: fmt::Arguments) {
+    let line = format_line(level, module, args);
+    MEMORY.write_line(line.as_str());
+}
+
+// format_line formats a timestamped log line.
+fn format_line(level: Level, module: &str, args: fmt::Arguments) -> LineBuf {
+    let t = time::uptime();
+    let mut line = LineBuf {
+        buf: [0; LINE_SIZE],
+        len: 0,
+    };
+    let _ = write!(
+        line,
+        "[{:5}.{:06}] core {} {} {}: ",
+        t.as_secs(),
+        t.subsec_micros(),
+        cpu::core_id(),
+        level.tag(),
+        module
+    );
+    let _ = line.write_fmt(args);
+    line.buf[line.len] = b'\n';
+    line.len += 1;
+    line
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+
+    fn lines(ring: &LogRing) -> Vec<Vec<u8>> {
+        let mut lines = Vec::new();
+        ring.for_each_line(|line| lines.push(line.to_vec()));
+        lines
+    }
+
+    #[test]
+    fn wrapped_ring_skips_the_partial_line() {
+        let mut ring = LogRing::new();
+        let line = [b'a'; 99];
+        for _ in 0..LOG_RING_SIZE / 100 + 1 {
+            ring.push(&line);
+            ring.push(b"\n");
+        }
+        let lines = lines(&ring);
+        assert_eq!(lines.len(), LOG_RING_SIZE / 100);
+        assert!(lines.iter().all(|l| l.len() == 100));
+    }
+
+    #[test]
+    fn wrap_on_a_line_boundary_keeps_the_first_line() {
+        let mut ring = LogRing::new();
+        let line = [b'a'; 63];
+        for _ in 0..LOG_RING_SIZE / 64 + 1 {
+            ring.push(&line);
+            ring.push(b"\n");
+        }
+        assert_eq!(lines(&ring).len(), LOG_RING_SIZE / 64);
+    }
+
+    #[test]
+    fn memory_sink_drops_lines_logged_while_it_is_held() {
+        let sink = MemorySink {
+            ring: Mutex::new(LogRing::new()),
+            holder: AtomicU64::new(0),
+        };
+        sink.with_ring(|_| sink.write_line("dropped\n"));
+        sink.write_line("kept\n");
+        let mut lines = Vec::new();
+        sink.for_each_line(|line| lines.push(line.to_vec()));
+        assert_eq!(lines, [b"kept\n"]);
+    }
+}
@@ -1,45 +1,96 @@
-#![no_std]
-#![no_main]
+// The kernel is built for aarch64-unknown-none. Built for the host it is
+// only good for `cargo test`, with registers backed by mock.rs.
+#![cfg_attr(target_os = "none", no_std)]
+#![cfg_attr(target_os = "none", no_main)]
 #![feature(naked_functions)]
 #![feature(asm_const)]
 #![feature(trait_alias)]
+#![cfg_attr(target_os = "none", feature(alloc_error_handler))]
+#![cfg_attr(target_os = "none", feature(custom_test_frameworks))]
+#![cfg_attr(target_os = "none", test_runner(crate::test::runner))]
+#![cfg_attr(target_os = "none", reexport_test_harness_main = "test_main")]
+
+extern crate alloc;
 
 mod asm;
 mod board;
+mod console;
 mod cpu;
 mod gpio;
+#[cfg(target_os = "none")]
+mod heap;
+mod intc;
+mod irq;
+mod local_intc;
+mod log;
 mod mmio;
+#[cfg(not(target_os = "none"))]
+mod mock;
+mod pl011;
 mod reg;
+#[cfg(any(feature = "trace-regs", test))]
+mod regtrace;
+mod ringbuf;
+mod semihosting;
+mod smp;
+mod systimer;
+#[cfg(all(test, target_os = "none"))]
+mod test;
+mod time;
+mod trap;
 mod uart;
 
+#[cfg(target_os = "none")]
 #[panic_handler]
 fn panic(info: &core::panic::PanicInfo) -> ! {
-    println!("{}", info);
+    emergency_println!("{}", info);
+    match trap::current() {
+        Some(trap::Kind::Sync) | Some(trap::Kind::SError) => {
+            emergency_println!("  in exception: {}", cpu::Esr::fetch())
+        }
+        Some(kind) => emergency_println!("  in {:?} handler", kind),
+        None => {}
+    }
+    #[cfg(test)]
+    test::failed();
+    #[cfg(not(test))]
     asm::halt();
 }
 
-fn init_heap(_base: usize, _top: usize) {
-    // TODO
-}
-
 // _start_rust is called from _start (in asm) with the stack set up.
+#[cfg(target_os = "none")]
 #[no_mangle]
 pub extern "C" fn _start_rust() -> ! {
-    println!("EL {:x}", cpu::current_el());
-    if cpu::core_id() != 0 {
-        println!("halting");
-        asm::halt();
+    let core = cpu::core_id();
+    if core == 0 {
+        console::select_from_cmdline();
+    }
+    info!("EL {:x}", cpu::current_el());
+    if core != 0 {
+        smp::secondary_start(core);
     }
 
-    asm::init_exceptions();
-    init_heap(board::HEAP_BASE, board::HEAP_TOP);
+    heap::init(board::heap_base(), board::HEAP_TOP);
+    irq::init();
+    console::init_irq();
+    smp::init_core();
+    #[cfg(test)]
+    test_main();
     main();
-    println!("Powering Off");
+    info!("Powering Off");
+    console::flush();
     asm::power_off();
 }
 
 // main is the first full rust function called.
 fn main() {
-    println!("Hello World!");
+    info!("Hello World!");
+    smp::start_secondaries(secondary_main);
+    info!("all cores online");
     //panic!("Test panic");
 }
+
+// secondary_main is run by cores 1..NCPU once they are released.
+fn secondary_main(_core: u64) {
+    info!("Hello from a secondary core");
+}
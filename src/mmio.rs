@@ -1,54 +1,130 @@
 use crate::reg;
-use crate::reg::Reg;
+use crate::reg::{ReadWrite, Readable, Reg, RegFields, RegRead, RegWrite, Writable};
+use core::marker::PhantomData;
 
-#[macro_export]
-macro_rules! mmio_reg32 {
-    ($struct_name:ident, $addr:expr) => {
-        struct $struct_name {
-            cached: u32,
-        }
+// read reads the T sized register at addr.
+// On the host it reads the mock register file instead.
+#[cfg(target_os = "none")]
+pub unsafe fn read<T: Copy>(addr: usize) -> T {
+    core::ptr::read_volatile(addr as *const T)
+}
 
-        impl $struct_name {
-            // new creates a new instances with pre-set cached value.
-            #[allow(dead_code)]
-            fn new(value: u32) -> Self {
-                $struct_name { cached: value }
-            }
+// write writes val to the T sized register at addr.
+// On the host it writes the mock register file instead.
+#[cfg(target_os = "none")]
+pub unsafe fn write<T: Copy>(addr: usize, val: T) {
+    core::ptr::write_volatile(addr as *mut T, val)
+}
 
-            // zero creates a new instance with zeroed cached value.
-            #[allow(dead_code)]
-            fn zero() -> Self {
-                Self::new(0)
-            }
+#[cfg(not(target_os = "none"))]
+pub unsafe fn read<T: Copy>(addr: usize) -> T {
+    crate::mock::read_mmio(addr)
+}
 
+#[cfg(not(target_os = "none"))]
+pub unsafe fn write<T: Copy>(addr: usize, val: T) {
+    crate::mock::write_mmio(addr, val)
+}
+
+// trace records a fetch or store of val at register name at addr when
+// built with the trace-regs feature, or for host tests, and does nothing
+// otherwise.
+#[inline(always)]
+pub fn trace<T: Into<u64>>(name: &'static str, addr: usize, store: bool, val: T) {
+    #[cfg(any(feature = "trace-regs", test))]
+    crate::regtrace::record(name, addr, store, val.into());
+    #[cfg(not(any(feature = "trace-regs", test)))]
+    let _ = (name, addr, store, val);
+}
+
+// mmio_reg! declares an MMIO register of type T (u8, u16, u32 or u64)
+// at a fixed address. An optional trailing rw, ro or wo gives its
+// access mode, which decides whether it has fetch and store. The
+// default is rw.
+#[macro_export]
+macro_rules! mmio_reg {
+    (@read $struct_name:ident, $t:ty, $addr:expr) => {
+        impl $struct_name {
             // fetch creates a new instances with fetched value.
             #[allow(dead_code)]
             fn fetch() -> Self {
                 let mut x = Self::zero();
-                x.fetch();
+                $crate::reg::RegRead::fetch(&mut x);
+                x
+            }
+
+            // wait_until creates a new instance once f is true of it.
+            #[allow(dead_code)]
+            fn wait_until(f: impl Fn(&Self) -> bool) -> Self {
+                let mut x = Self::zero();
+                $crate::reg::RegRead::wait_until(&mut x, f);
                 x
             }
         }
 
-        impl Reg<u32> for $struct_name {
+        impl $crate::reg::RegRead for $struct_name {
+            fn fetch(&mut self) -> &mut Self {
+                let val = unsafe { $crate::mmio::read::<$t>($addr) };
+                $crate::mmio::trace(stringify!($struct_name), $addr, false, val);
+                self.set_value(val);
+                self
+            }
+        }
+    };
+    (@write $struct_name:ident, $t:ty, $addr:expr) => {
+        impl $crate::reg::RegWrite for $struct_name {
             fn store(&self) {
                 let val = self.get_value();
+                $crate::mmio::trace(stringify!($struct_name), $addr, true, val);
                 unsafe {
-                    core::ptr::write_volatile($addr as *mut u32, val);
+                    $crate::mmio::write::<$t>($addr, val);
                 }
             }
+        }
+    };
+    ($struct_name:ident, $t:ty, $addr:expr) => {
+        $crate::mmio_reg!($struct_name, $t, $addr, rw);
+    };
+    ($struct_name:ident, $t:ty, $addr:expr, rw) => {
+        $crate::mmio_reg!(@base $struct_name, $t);
+        $crate::mmio_reg!(@read $struct_name, $t, $addr);
+        $crate::mmio_reg!(@write $struct_name, $t, $addr);
+    };
+    ($struct_name:ident, $t:ty, $addr:expr, ro) => {
+        $crate::mmio_reg!(@base $struct_name, $t);
+        $crate::mmio_reg!(@read $struct_name, $t, $addr);
+    };
+    ($struct_name:ident, $t:ty, $addr:expr, wo) => {
+        $crate::mmio_reg!(@base $struct_name, $t);
+        $crate::mmio_reg!(@write $struct_name, $t, $addr);
+    };
+    (@base $struct_name:ident, $t:ty) => {
+        struct $struct_name {
+            cached: $t,
+        }
 
-            fn fetch(&mut self) -> &mut Self {
-                let val = unsafe { core::ptr::read_volatile($addr as *mut u32) };
-                self.set_value(val);
-                self
+        impl $struct_name {
+            // new creates a new instances with pre-set cached value.
+            #[allow(dead_code)]
+            fn new(value: $t) -> Self {
+                $struct_name { cached: value }
             }
 
-            fn get_value(&self) -> u32 {
+            // zero creates a new instance with zeroed cached value.
+            #[allow(dead_code)]
+            fn zero() -> Self {
+                Self::new(0)
+            }
+        }
+
+        impl Reg for $struct_name {
+            type Value = $t;
+
+            fn get_value(&self) -> $t {
                 self.cached
             }
 
-            fn set_value(&mut self, val: u32) -> &mut Self {
+            fn set_value(&mut self, val: $t) -> &mut Self {
                 self.cached = val;
                 self
             }
@@ -56,44 +132,51 @@ macro_rules! mmio_reg32 {
     };
 }
 
-// Generic mmio with an explicit address.
-pub struct Mmio<T> {
+// mmio_reg32! declares a 32-bit MMIO register, like mmio_reg!.
+#[macro_export]
+macro_rules! mmio_reg32 {
+    ($struct_name:ident, $addr:expr $(, $access:ident)?) => {
+        $crate::mmio_reg!($struct_name, u32, $addr $(, $access)?);
+    };
+}
+
+// Generic mmio with an explicit address and access mode A.
+// name is what the register is traced as.
+pub struct Mmio<T, A = ReadWrite> {
+    name: &'static str,
     addr: usize,
     cached: T,
+    access: PhantomData<A>,
 }
 
-impl<T: reg::Trait<T>> Mmio<T> {
+impl<T: reg::Trait<T>, A> Mmio<T, A> {
     #[allow(dead_code)]
-    pub fn new(addr: usize, val: T) -> Self {
-        Mmio::<T> { addr: addr, cached: val }
+    pub fn new(name: &'static str, addr: usize, val: T) -> Self {
+        Mmio {
+            name,
+            addr,
+            cached: val,
+            access: PhantomData,
+        }
     }
 
     #[allow(dead_code)]
-    pub fn zero(addr: usize) -> Self {
-        Self::new(addr, T::from(0))
+    pub fn zero(name: &'static str, addr: usize) -> Self {
+        Self::new(name, addr, T::from(0))
     }
+}
 
+impl<T: reg::Trait<T>, A: Readable> Mmio<T, A> {
     #[allow(dead_code)]
-    pub fn fetch(addr: usize) -> Self {
-        let mut x = Self::new(addr, T::from(0));
-        x.fetch();
+    pub fn fetch(name: &'static str, addr: usize) -> Self {
+        let mut x = Self::new(name, addr, T::from(0));
+        RegRead::fetch(&mut x);
         x
     }
 }
 
-impl<T: reg::Trait<T>> Reg<T> for Mmio<T> {
-    fn store(&self) {
-        let val = self.get_value();
-        unsafe {
-            core::ptr::write_volatile(self.addr as *mut T, val);
-        }
-    }
-
-    fn fetch(&mut self) -> &mut Self {
-        let val = unsafe { core::ptr::read_volatile(self.addr as *mut T) };
-        self.set_value(val);
-        self
-    }
+impl<T: reg::Trait<T>, A> Reg for Mmio<T, A> {
+    type Value = T;
 
     fn get_value(&self) -> T {
         self.cached
@@ -105,48 +188,366 @@ impl<T: reg::Trait<T>> Reg<T> for Mmio<T> {
     }
 }
 
-// Reg32Array is an array of 32-bit MMIO registers with "safe" fetch and store methods.
-// The implementer must ensure that the associated ADDR and LEN are safe to read and write.
-// TODO: genericize this for types other than u32 if ever needed.
-pub trait Reg32Array {
-    const ADDR: usize;
-    const SIZE: usize;
+impl<T: reg::Trait<T>, A: Readable> RegRead for Mmio<T, A> {
+    fn fetch(&mut self) -> &mut Self {
+        let val = unsafe { read::<T>(self.addr) };
+        trace(self.name, self.addr, false, val);
+        self.set_value(val);
+        self
+    }
+}
 
-    // get returns a Reg<u32> for this index with the cached value zeroed.
-    #[allow(dead_code)]
-    fn index(&self, idx: usize) -> Mmio<u32> {
-        if idx >= Self::SIZE {
-            panic!("idx {} is too big!", idx);
+impl<T: reg::Trait<T>, A: Writable> RegWrite for Mmio<T, A> {
+    fn store(&self) {
+        let val = self.get_value();
+        trace(self.name, self.addr, true, val);
+        unsafe {
+            write(self.addr, val);
         }
-        let addr = Self::ADDR + 4 * idx;
-        Mmio::<u32>::zero(addr)
     }
+}
+
+// Mmio has no fields, so a read-modify-write stores back what it read.
+macro_rules! mmio_no_fields {
+    ($($t:ty),*) => {
+        $(
+            impl<A> RegFields for Mmio<$t, A> {
+                const RMW_MASK: $t = 0;
+            }
+        )*
+    };
+}
+
+mmio_no_fields!(u8, u16, u32, u64);
 
-    // get returns a Reg<u32> for this index with the cached value fetched.
+// RegArray is an array of N MMIO registers of type T, stride bytes apart,
+// with access mode A. Each register is traced as name.
+pub struct RegArray<T, const N: usize, A = ReadWrite> {
+    name: &'static str,
+    addr: usize,
+    stride: usize,
+    reg: PhantomData<(T, A)>,
+}
+
+impl<T: reg::Trait<T>, const N: usize, A> RegArray<T, N, A> {
+    // at returns the array named name with its first register at addr.
+    //
+    // Safety: addr + i * stride must be a T register for all i < N.
+    pub const unsafe fn at(name: &'static str, addr: usize, stride: usize) -> Self {
+        RegArray {
+            name,
+            addr,
+            stride,
+            reg: PhantomData,
+        }
+    }
+
+    // index returns register idx with the cached value zeroed.
     #[allow(dead_code)]
-    fn index_fetch(&self, idx: usize) -> Mmio<u32> {
-        if idx >= Self::SIZE {
+    pub fn index(&self, idx: usize) -> Mmio<T, A> {
+        Mmio::zero(self.name, self.addr_of(idx))
+    }
+
+    fn addr_of(&self, idx: usize) -> usize {
+        if idx >= N {
             panic!("idx {} is too big!", idx);
         }
-        let addr = Self::ADDR + 4 * idx;
-        Mmio::<u32>::fetch(addr)
+        self.addr + self.stride * idx
+    }
+}
+
+impl<T: reg::Trait<T>, const N: usize, A: Readable> RegArray<T, N, A> {
+    // index_fetch returns register idx with the cached value fetched.
+    #[allow(dead_code)]
+    pub fn index_fetch(&self, idx: usize) -> Mmio<T, A> {
+        let mut reg = self.index(idx);
+        RegRead::fetch(&mut reg);
+        reg
     }
 }
 
+// mmio_reg_array! declares an array of MMIO registers of type T at a
+// fixed address. They are packed together unless a stride in bytes is
+// given, and take an optional rw, ro or wo access mode like mmio_reg!:
+//
+//   mmio_reg_array!(CoreTimerIrqCntl, u32, 4, board::LOCAL_BASE + 0x40);
+//   mmio_reg_array!(Fifo, u8, 4, FIFO_BASE, stride = 4, wo);
+//
+// The declared type derefs to a RegArray, so CoreTimerIrqCntl::new().index(i)
+// is register i.
 #[macro_export]
-macro_rules! mmio_reg32_array {
-    ($struct_name:ident, $size:expr, $addr:expr) => {
-        struct $struct_name {}
+macro_rules! mmio_reg_array {
+    ($struct_name:ident, $t:ty, $size:expr, $addr:expr, stride = $stride:expr $(, $access:ident)?) => {
+        $crate::mmio_reg_array!(@decl $struct_name, $t, $size, $addr, $stride, $($access)? rw);
+    };
+    ($struct_name:ident, $t:ty, $size:expr, $addr:expr $(, $access:ident)?) => {
+        $crate::mmio_reg_array!(@decl $struct_name, $t, $size, $addr,
+            core::mem::size_of::<$t>(), $($access)? rw);
+    };
+    (@decl $struct_name:ident, $t:ty, $size:expr, $addr:expr, $stride:expr, $access:ident $($default:ident)?) => {
+        struct $struct_name($crate::mmio::RegArray<$t, { $size }, $crate::access!($access)>);
 
         impl $struct_name {
             fn new() -> Self {
-                $struct_name {}
+                let addr: usize = $addr;
+                let stride: usize = $stride;
+                let name = stringify!($struct_name);
+                let regs = unsafe { $crate::mmio::RegArray::at(name, addr, stride) };
+                $struct_name(regs)
+            }
+        }
+
+        impl core::ops::Deref for $struct_name {
+            type Target = $crate::mmio::RegArray<$t, { $size }, $crate::access!($access)>;
+
+            fn deref(&self) -> &Self::Target {
+                &self.0
+            }
+        }
+    };
+}
+
+// mmio_reg32_array! declares an array of 32-bit MMIO registers, like
+// mmio_reg_array!.
+#[macro_export]
+macro_rules! mmio_reg32_array {
+    ($struct_name:ident, $size:expr, $addr:expr $(, $access:ident)?) => {
+        $crate::mmio_reg_array!($struct_name, u32, $size, $addr $(, $access)?);
+    };
+}
+
+// Block is a peripheral's registers, laid out by register_block! as B,
+// at a base address chosen at runtime.
+pub struct Block<B> {
+    base: usize,
+    layout: PhantomData<B>,
+}
+
+impl<B> Clone for Block<B> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<B> Copy for Block<B> {}
+
+impl<B> Block<B> {
+    // at returns the block of registers at base.
+    //
+    // Safety: base must be the address of a peripheral laid out as B
+    // (or, in host tests, any address the mock register file can use).
+    pub const unsafe fn at(base: usize) -> Self {
+        Block { base, layout: PhantomData }
+    }
+
+    // base returns the address of the first register.
+    #[allow(dead_code)]
+    pub fn base(&self) -> usize {
+        self.base
+    }
+}
+
+// register_block! declares a peripheral's 32-bit registers as a
+// #[repr(C)] struct, one entry per register or reserved gap:
+//
+//   register_block!(
+//       pub struct AuxRegs {
+//           0x04 => enables: AuxEnables,
+//           0x08 => _reserved0: [u32; 14],
+//           0x40 => mu_io: AuxMuIo,
+//           0x54 => mu_lsr: AuxMuLsr ro,
+//       }
+//   );
+//
+// Each register gets a type like mmio_reg32!'s, with an optional rw,
+// ro or wo access mode, that reg_fields! can add fields to, and an
+// accessor on Block<AuxRegs> that returns it zeroed at its address:
+//
+//   const AUX: Block<AuxRegs> = unsafe { Block::at(board::AUX_BASE) };
+//   while !AUX.mu_lsr().fetch().tx_empty() {}
+//
+// A compile-time assert checks that every entry is at its offset, so
+// a missing or mis-sized gap doesn't compile. dump!(AUX) prints every
+// readable register, so each needs reg_fields! for its Debug. Registers
+// whose reads change something, like popping a FIFO or clearing a
+// status bit, are marked side_effects after the access mode, and
+// dump! leaves them alone:
+//
+//       0x40 => mu_io: AuxMuIo side_effects,
+//       0x54 => mu_lsr: AuxMuLsr ro side_effects,
+#[macro_export]
+macro_rules! register_block {
+    (
+        $(#[$meta:meta])*
+        $vis:vis struct $name:ident {
+            $($body:tt)*
+        }
+    ) => {
+        $crate::register_block!(@munch [$(#[$meta])* $vis $name] [] $($body)*);
+    };
+    (@munch $head:tt [$($acc:tt)*]
+        $off:literal => $field:ident : [$t:ty; $n:literal] $(, $($rest:tt)*)?
+    ) => {
+        $crate::register_block!(@munch $head
+            [$($acc)* ($off, $field, [$t; $n], (reserved))] $($($rest)*)?);
+    };
+    (@munch $head:tt [$($acc:tt)*]
+        $off:literal => $field:ident : $reg:ident $access:ident side_effects $(, $($rest:tt)*)?
+    ) => {
+        $crate::register_block!(@munch $head
+            [$($acc)* ($off, $field, u32, (reg $reg $access skip))] $($($rest)*)?);
+    };
+    (@munch $head:tt [$($acc:tt)*]
+        $off:literal => $field:ident : $reg:ident side_effects $(, $($rest:tt)*)?
+    ) => {
+        $crate::register_block!(@munch $head
+            [$($acc)* ($off, $field, u32, (reg $reg rw skip))] $($($rest)*)?);
+    };
+    (@munch $head:tt [$($acc:tt)*]
+        $off:literal => $field:ident : $reg:ident $access:ident $(, $($rest:tt)*)?
+    ) => {
+        $crate::register_block!(@munch $head
+            [$($acc)* ($off, $field, u32, (reg $reg $access dump))] $($($rest)*)?);
+    };
+    (@munch $head:tt [$($acc:tt)*]
+        $off:literal => $field:ident : $reg:ident $(, $($rest:tt)*)?
+    ) => {
+        $crate::register_block!(@munch $head
+            [$($acc)* ($off, $field, u32, (reg $reg rw dump))] $($($rest)*)?);
+    };
+    (@munch [$(#[$meta:meta])* $vis:vis $name:ident]
+        [$(($off:literal, $field:ident, $t:ty, $kind:tt))*]
+    ) => {
+        $(#[$meta])*
+        #[allow(dead_code)]
+        #[repr(C)]
+        $vis struct $name {
+            $($field: $t,)*
+        }
+
+        $(
+            const _: () = assert!(core::mem::offset_of!($name, $field) == $off);
+        )*
+
+        #[allow(dead_code)]
+        impl $crate::mmio::Block<$name> {
+            $( $crate::register_block!(@accessor $vis $name, $field, $kind); )*
+        }
+
+        impl $crate::reg::Dump for $crate::mmio::Block<$name> {
+            fn dump(self) {
+                let block = self;
+                $crate::print!("{} at {:#x}:\n", stringify!($name), block.base());
+                $( $crate::register_block!(@dump block, $name, $field, $kind); )*
+            }
+        }
+
+        $( $crate::register_block!(@reg $vis $kind); )*
+    };
+    (@accessor $vis:vis $name:ident, $field:ident, (reserved)) => {};
+    (@accessor $vis:vis $name:ident, $field:ident, (reg $reg:ident $access:ident $dump:ident)) => {
+        $vis fn $field(&self) -> $reg {
+            $reg {
+                addr: self.base() + core::mem::offset_of!($name, $field),
+                cached: 0,
             }
         }
+    };
+    (@dump $block:ident, $name:ident, $field:ident, (reg $reg:ident wo $dump:ident)) => {};
+    (@dump $block:ident, $name:ident, $field:ident, (reg $reg:ident $access:ident skip)) => {};
+    (@dump $block:ident, $name:ident, $field:ident, (reg $reg:ident $access:ident dump)) => {
+        $crate::print!(
+            "  {:#05x} {:?}\n",
+            core::mem::offset_of!($name, $field),
+            $crate::reg::RegRead::fetch(&mut $block.$field())
+        );
+    };
+    (@dump $block:ident, $name:ident, $field:ident, (reserved)) => {};
+    (@reg $vis:vis (reserved)) => {};
+    (@reg $vis:vis (reg $reg:ident rw $dump:ident)) => {
+        $crate::register_block!(@reg_base $vis $reg);
+        $crate::register_block!(@reg_read $reg);
+        $crate::register_block!(@reg_write $reg);
+    };
+    (@reg $vis:vis (reg $reg:ident ro $dump:ident)) => {
+        $crate::register_block!(@reg_base $vis $reg);
+        $crate::register_block!(@reg_read $reg);
+    };
+    (@reg $vis:vis (reg $reg:ident wo $dump:ident)) => {
+        $crate::register_block!(@reg_base $vis $reg);
+        $crate::register_block!(@reg_write $reg);
+    };
+    (@reg_base $vis:vis $reg:ident) => {
+        #[allow(dead_code)]
+        $vis struct $reg {
+            addr: usize,
+            cached: u32,
+        }
+
+        impl $crate::reg::Reg for $reg {
+            type Value = u32;
+
+            fn get_value(&self) -> u32 {
+                self.cached
+            }
 
-        impl Reg32Array for $struct_name {
-            const ADDR: usize = $addr;
-            const SIZE: usize = $size;
+            fn set_value(&mut self, val: u32) -> &mut Self {
+                self.cached = val;
+                self
+            }
+        }
+    };
+    (@reg_read $reg:ident) => {
+        impl $crate::reg::RegRead for $reg {
+            fn fetch(&mut self) -> &mut Self {
+                self.cached = unsafe { $crate::mmio::read::<u32>(self.addr) };
+                $crate::mmio::trace(stringify!($reg), self.addr, false, self.cached);
+                self
+            }
         }
     };
+    (@reg_write $reg:ident) => {
+        impl $crate::reg::RegWrite for $reg {
+            fn store(&self) {
+                $crate::mmio::trace(stringify!($reg), self.addr, true, self.cached);
+                unsafe { $crate::mmio::write::<u32>(self.addr, self.cached) };
+            }
+        }
+    };
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::Reg;
+    use crate::mock::{self, Loc};
+    use crate::reg::RegWrite;
+
+    mmio_reg!(Counter, u64, 0x2000, ro);
+    mmio_reg!(Byte, u8, 0x2010);
+    mmio_reg_array!(Fifo, u8, 4, 0x2100, stride = 4, wo);
+    mmio_reg_array!(Counters, u64, 2, 0x2200, ro);
+
+    #[test]
+    fn registers_have_their_width() {
+        mock::reset();
+        mock::preset(Loc::Mmio(0x2000), 1 << 40);
+        assert_eq!(Counter::fetch().get_value(), 1 << 40);
+        Byte::new(0xa5).store();
+        assert_eq!(mock::writes(0x2010, 1), [(0, 0xa5)]);
+    }
+
+    #[test]
+    fn arrays_are_stride_apart() {
+        mock::reset();
+        Fifo::new().index(3).set_value(7).store();
+        assert_eq!(mock::writes(0x2100, 0x10), [(12, 7)]);
+        mock::preset(Loc::Mmio(0x2208), 5 << 32);
+        assert_eq!(Counters::new().index_fetch(1).get_value(), 5 << 32);
+    }
+
+    #[test]
+    #[should_panic]
+    fn arrays_check_the_index() {
+        Counters::new().index(2);
+    }
 }
@@ -0,0 +1,130 @@
+/*
+ * mock.rs
+ * In-memory register file for host tests.
+ *
+ * When built for the host (cargo test --target x86_64-unknown-linux-gnu)
+ * mmio_reg32!, register_block!, Mmio and cpu_reg64! registers read and
+ * write here instead of the hardware. Each test thread gets its own
+ * register file, and every access is recorded so tests can check what a
+ * driver did and in what order. Registers that were never written read as zero.
+ */
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::mem::size_of;
+use std::vec::Vec;
+
+// Loc is a register: an MMIO address or a system register name.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Loc {
+    Mmio(usize),
+    Sys(&'static str),
+}
+
+// Access is a recorded register read or write, with its value.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    Read(Loc, u64),
+    Write(Loc, u64),
+}
+
+#[derive(Default)]
+struct RegFile {
+    values: HashMap<Loc, u64>,
+    // later holds a value a register changes to after some more reads.
+    later: HashMap<Loc, (usize, u64)>,
+    log: Vec<Access>,
+}
+
+thread_local! {
+    static REGS: RefCell<RegFile> = RefCell::new(RegFile::default());
+}
+
+// reset clears all registers and the access log.
+#[cfg(test)]
+pub fn reset() {
+    REGS.with(|r| *r.borrow_mut() = RegFile::default());
+}
+
+// preset sets a register without recording an access, to set up
+// what a driver will read.
+#[cfg(test)]
+pub fn preset(loc: Loc, val: u64) {
+    REGS.with(|r| r.borrow_mut().values.insert(loc, val));
+}
+
+// preset_after makes a register read as its current value n more
+// times and as val after that, like a status bit that takes a while to
+// be set.
+#[cfg(test)]
+pub fn preset_after(loc: Loc, n: usize, val: u64) {
+    REGS.with(|r| r.borrow_mut().later.insert(loc, (n, val)));
+}
+
+// value returns a register's value without recording an access.
+#[cfg(test)]
+pub fn value(loc: Loc) -> u64 {
+    REGS.with(|r| r.borrow().values.get(&loc).copied().unwrap_or(0))
+}
+
+// take_log returns the accesses recorded since the last call or reset.
+#[cfg(test)]
+pub fn take_log() -> Vec<Access> {
+    REGS.with(|r| std::mem::take(&mut r.borrow_mut().log))
+}
+
+// writes returns the writes recorded since the last call or reset,
+// with addresses in [base, base+len), as (offset, value) pairs.
+#[cfg(test)]
+pub fn writes(base: usize, len: usize) -> Vec<(usize, u64)> {
+    take_log()
+        .into_iter()
+        .filter_map(|a| match a {
+            Access::Write(Loc::Mmio(addr), val) if addr >= base && addr < base + len => {
+                Some((addr - base, val))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+pub fn read(loc: Loc) -> u64 {
+    REGS.with(|r| {
+        let mut r = r.borrow_mut();
+        match r.later.get_mut(&loc) {
+            Some((0, val)) => {
+                let val = *val;
+                r.values.insert(loc, val);
+                r.later.remove(&loc);
+            }
+            Some((n, _)) => *n -= 1,
+            None => {}
+        }
+        let val = r.values.get(&loc).copied().unwrap_or(0);
+        r.log.push(Access::Read(loc, val));
+        val
+    })
+}
+
+pub fn write(loc: Loc, val: u64) {
+    REGS.with(|r| {
+        let mut r = r.borrow_mut();
+        r.values.insert(loc, val);
+        r.log.push(Access::Write(loc, val));
+    })
+}
+
+// read_mmio and write_mmio back mmio::read and mmio::write for any
+// integer type up to 64 bits.
+pub fn read_mmio<T: Copy>(addr: usize) -> T {
+    assert!(size_of::<T>() <= size_of::<u64>());
+    let val = read(Loc::Mmio(addr));
+    unsafe { (&val as *const u64 as *const T).read() }
+}
+
+pub fn write_mmio<T: Copy>(addr: usize, val: T) {
+    assert!(size_of::<T>() <= size_of::<u64>());
+    let mut wide = 0u64;
+    unsafe { (&mut wide as *mut u64 as *mut T).write(val) };
+    write(Loc::Mmio(addr), wide);
+}
@@ -0,0 +1,316 @@
+/*
+ * pl011.rs
+ * BCM2837 PL011 UART (UART0) support.
+ * Ref: BCM2837 ARM Peripherals, section 13.
+ */
+
+use crate::console::Console;
+use crate::irq::{self, Source};
+use crate::reg::{Reg, RegWrite};
+use crate::ringbuf::RingBuffer;
+use crate::{asm, board, cpu, field_enum, gpio, mmio_reg32, reg_fields};
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+
+const UART0_IRQ: u32 = 57;
+
+mmio_reg32!(UartDr, board::PL011_BASE);
+mmio_reg32!(UartFr, board::PL011_BASE + 0x18, ro);
+mmio_reg32!(UartIbrd, board::PL011_BASE + 0x24);
+mmio_reg32!(UartFbrd, board::PL011_BASE + 0x28);
+mmio_reg32!(UartLcrh, board::PL011_BASE + 0x2c);
+mmio_reg32!(UartCr, board::PL011_BASE + 0x30);
+mmio_reg32!(UartIfls, board::PL011_BASE + 0x34);
+mmio_reg32!(UartImsc, board::PL011_BASE + 0x38);
+mmio_reg32!(UartIcr, board::PL011_BASE + 0x44, wo);
+
+reg_fields!(UartDr {
+    data: ro @ 0..8,
+});
+
+reg_fields!(UartFr {
+    busy: ro @ 3,
+    rx_empty: ro @ 4,
+    tx_full: ro @ 5,
+});
+
+reg_fields!(UartIbrd {
+    divisor: rw @ 0..16,
+});
+
+reg_fields!(UartFbrd {
+    fraction: rw @ 0..6,
+});
+
+field_enum!(
+    // WordLen is the number of data bits, in UARTLCR_H.
+    pub enum WordLen {
+        Five = 0,
+        Six = 1,
+        Seven = 2,
+        Eight = 3,
+    }
+);
+
+reg_fields!(UartLcrh {
+    fifo_enb: rw @ 4,
+    word_len: rw @ 5..7 as WordLen,
+});
+
+reg_fields!(UartCr {
+    uart_enb: rw @ 0,
+    xmit_enb: rw @ 8,
+    recv_enb: rw @ 9,
+});
+
+field_enum!(
+    // FifoLevel is how full a fifo gets before it interrupts, in UARTIFLS.
+    pub enum FifoLevel {
+        OneEighth = 0,
+        OneQuarter = 1,
+        OneHalf = 2,
+        ThreeQuarters = 3,
+        SevenEighths = 4,
+    }
+);
+
+reg_fields!(UartIfls {
+    xmit_level: rw @ 0..3 as FifoLevel,
+    recv_level: rw @ 3..6 as FifoLevel,
+});
+
+reg_fields!(UartImsc {
+    recv_irq: rw @ 4,
+    xmit_irq: rw @ 5,
+    recv_timeout_irq: rw @ 6,
+});
+
+impl UartIcr {
+    const ALL: u32 = 0x7ff;
+}
+
+reg_fields!(UartIcr {
+    clear_recv: wo @ 4,
+    clear_xmit: wo @ 5,
+    clear_recv_timeout: wo @ 6,
+});
+
+// set_baud programs the baud rate divisor, clock / (16 * baud),
+// as a 16.6 fixed point number rounded to the nearest 1/64th.
+fn set_baud(baud: u32) {
+    let div64 = (board::PL011_CLOCK as u64 * 4 + baud as u64 / 2) / baud as u64;
+    UartIbrd::zero().set_divisor((div64 >> 6) as u32).store();
+    UartFbrd::zero().set_fraction((div64 & 0x3f) as u32).store();
+}
+
+// init enables and initializes the PL011 uart (uart0).
+fn init() {
+    UartCr::zero().store(); // disabled
+    UartFr::wait_until(|r| !r.busy());
+    UartLcrh::zero().store(); // flush fifos
+
+    gpio::pin_use_as_alt0(board::PL011_TX_PIN);
+    gpio::pin_use_as_alt0(board::PL011_RX_PIN);
+
+    UartImsc::zero().store(); // no interrupts
+    XMIT_IRQ.store(false, Ordering::Relaxed);
+    UartIcr::new(UartIcr::ALL).store(); // clear interrupts
+    set_baud(115200);
+    UartLcrh::zero()
+        .set_word_len(WordLen::Eight)
+        .set_fifo_enb(true)
+        .store(); // 8bit, fifos on
+    UartIfls::zero()
+        .set_xmit_level(FifoLevel::OneEighth)
+        .set_recv_level(FifoLevel::OneHalf)
+        .store();
+    UartCr::zero()
+        .set_uart_enb(true)
+        .set_xmit_enb(true)
+        .set_recv_enb(true)
+        .store();
+}
+
+// write_char writes a single character. It uses polling to wait
+// for room in the transmit fifo.
+fn write_char(ch: u8) {
+    UartFr::wait_until(|r| !r.tx_full());
+    UartDr::new(ch as u32).store();
+}
+
+// RX holds received bytes until they are read.
+static RX: RingBuffer<1024> = RingBuffer::new();
+
+// TX holds bytes waiting to be sent by the transmit interrupt.
+// The writer (under console::WRITER) is its only producer, and its
+// consumers (the writer, the IRQ handler and flush) take TX_DRAIN.
+static TX: RingBuffer<4096> = RingBuffer::new();
+static TX_DRAIN: Mutex<()> = Mutex::new(());
+
+// TX_IRQ is set once the transmit interrupt can be used.
+static TX_IRQ: AtomicBool = AtomicBool::new(false);
+
+// IMSC is shared by the writer and the IRQ handler, which may run on
+// different cores. It is only updated under IMSC_LOCK with IRQs masked.
+static IMSC_LOCK: Mutex<()> = Mutex::new(());
+
+// XMIT_IRQ is whether the transmit interrupt is on.
+static XMIT_IRQ: AtomicBool = AtomicBool::new(false);
+
+// set_xmit_irq turns the transmit interrupt on or off. Only drain calls
+// it, under TX_DRAIN, so IMSC is only touched when the state changes.
+fn set_xmit_irq(enable: bool) {
+    if XMIT_IRQ.load(Ordering::Relaxed) == enable {
+        return;
+    }
+    cpu::without_irqs(|| {
+        let _imsc = IMSC_LOCK.lock();
+        UartImsc::zero().modify(|r| r.set_xmit_irq(enable));
+    });
+    XMIT_IRQ.store(enable, Ordering::Relaxed);
+}
+
+// drain moves queued bytes into the transmit fifo until it is full.
+// The PL011 only interrupts when the fifo level falls through the
+// trigger level, so the transmit interrupt is left on only while bytes
+// are still queued behind a full fifo.
+fn drain() {
+    let _drain = match TX_DRAIN.try_lock() {
+        Some(guard) => guard,
+        None => return, // someone else is already draining
+    };
+    loop {
+        while !UartFr::fetch().tx_full() {
+            match TX.pop() {
+                Some(ch) => UartDr::new(ch as u32).store(),
+                None => break,
+            }
+        }
+        if !TX.is_empty() {
+            set_xmit_irq(true); // fifo is full, wait for the interrupt
+            return;
+        }
+        set_xmit_irq(false);
+        // Catch bytes queued after the check, before the irq went off.
+        if TX.is_empty() {
+            return;
+        }
+    }
+}
+
+// flush synchronously sends everything queued in TX.
+fn flush() {
+    let _drain = TX_DRAIN.lock();
+    while let Some(ch) = TX.pop() {
+        write_char(ch);
+    }
+}
+
+// handle_irq moves everything in the receive FIFO into RX and
+// refills the transmit FIFO from TX.
+// Bytes are dropped if RX is full.
+fn handle_irq() {
+    while !UartFr::fetch().rx_empty() {
+        RX.push(UartDr::fetch().data() as u8);
+    }
+    UartIcr::zero().write(|w| {
+        w.set_clear_recv(true)
+            .set_clear_recv_timeout(true)
+            .set_clear_xmit(true)
+    });
+    asm::sev(); // wake readers
+    drain();
+}
+
+// Pl011 is the PL011 uart as a console.
+pub struct Pl011;
+
+pub static PL011: Pl011 = Pl011;
+
+impl Console for Pl011 {
+    fn init(&self) {
+        init();
+    }
+
+    fn init_irq(&self) {
+        irq::register(Source::Gpu(UART0_IRQ), handle_irq);
+        // The timeout interrupt catches bytes left below the fifo level.
+        UartImsc::zero().modify(|r| r.set_recv_irq(true).set_recv_timeout_irq(true));
+        TX_IRQ.store(true, Ordering::Release);
+    }
+
+    // write_byte queues ch and tops up the transmit fifo, leaving the
+    // rest to the transmit interrupt. Before interrupts are set up, and
+    // whenever they are masked, it writes synchronously instead, after
+    // flushing the queue.
+    fn write_byte(&self, ch: u8) {
+        if !TX_IRQ.load(Ordering::Acquire) || cpu::irqs_masked() {
+            flush();
+            write_char(ch);
+            return;
+        }
+        while !TX.push(ch) {
+            // Full, let the interrupt make room.
+            drain();
+            core::hint::spin_loop();
+        }
+        drain();
+    }
+
+    fn flush(&self) {
+        flush();
+        UartFr::wait_until(|r| !r.busy());
+    }
+
+    fn try_read_byte(&self) -> Option<u8> {
+        RX.pop()
+    }
+
+    fn recover(&self) {
+        let cr = UartCr::fetch();
+        if !cr.uart_enb() || !cr.xmit_enb() {
+            init();
+        }
+    }
+
+    // emergency_write_byte sends any queued output first, unless the
+    // queue is being drained by someone who may never finish.
+    fn emergency_write_byte(&self, ch: u8) {
+        if let Some(_drain) = TX_DRAIN.try_lock() {
+            while let Some(ch) = TX.pop() {
+                write_char(ch);
+            }
+        }
+        write_char(ch);
+    }
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+    use crate::mock::{self, Loc};
+
+    const FR: usize = board::PL011_BASE + 0x18;
+
+    #[test]
+    fn transmit_irq_sends_what_the_full_fifo_left_queued() {
+        mock::reset();
+        TX_IRQ.store(true, Ordering::Release);
+        mock::preset(Loc::Mmio(FR), 1 << 4); // rx empty, room to send
+        PL011.write_byte(b'a');
+        mock::preset(Loc::Mmio(FR), 1 << 4 | 1 << 5); // tx full
+        PL011.write_byte(b'b');
+        mock::preset(Loc::Mmio(FR), 1 << 4);
+        handle_irq();
+        assert_eq!(
+            mock::writes(board::PL011_BASE, 0x48),
+            [
+                (0x00, b'a' as u64), // DR
+                (0x38, 1 << 5),      // IMSC: transmit irq on
+                (0x44, 0x70),        // ICR
+                (0x00, b'b' as u64), // DR
+                (0x38, 0),           // IMSC: transmit irq off
+            ]
+        );
+    }
+}
@@ -1,3 +1,6 @@
+use crate::time::{self, Duration};
+use core::fmt;
+
 pub trait Trait<T> = Copy
     + From<bool>
     + From<u8>
@@ -8,27 +11,36 @@ pub trait Trait<T> = Copy
     + core::ops::Shr<u8, Output = T>
     + core::ops::Sub<Output = T>
     + core::ops::Not<Output = T>
-    + core::fmt::Display;
-
-// Reg<T> is a T (some primitive integer/bit vector) MMIO register with "safe" fetch and store methods.
-pub trait Reg<T: Trait<T>> {
-    // store writes the current value into the hardware register.
-    fn store(&self);
+    + core::fmt::Display
+    + core::fmt::LowerHex
+    + Into<u64>;
 
-    // fetch reads the hardware register and returns it.
-    fn fetch(&mut self) -> &mut Self;
+// Reg is an MMIO or system register holding a Value (some primitive
+// integer/bit vector). Registers that can be read implement RegRead,
+// and registers that can be written implement RegWrite, so a status
+// register has no store and a write-only register has no fetch.
+//
+// Registers at a fixed address (mmio_reg!, cpu_reg64!) also have
+// associated fetch() and wait_until(f), which start from a new instance.
+// Elsewhere zero() is only that starting instance: read and modify
+// fetch before they look at the value, so UartImsc::zero().modify(f)
+// changes the register as it is, not zero. There is no associated
+// modify, as only registers with reg_fields! have one.
+pub trait Reg {
+    type Value: Trait<Self::Value>;
 
     // get_value gets the currently cached value.
-    fn get_value(&self) -> T;
+    fn get_value(&self) -> Self::Value;
 
     // set_value sets the currently cached value.
     // Use store() to commit it to the register.
-    fn set_value(&mut self, val: T) -> &mut Self;
+    fn set_value(&mut self, val: Self::Value) -> &mut Self;
 
     // set_bits sets bits [shift .. shift+sz] to val.
-    fn set_bits(&mut self, shift: u8, sz: u8, val: T) -> &mut Self {
-        let mask = (T::from(1) << sz) - T::from(1);
-        if val & !mask != T::from(0) {
+    fn set_bits(&mut self, shift: u8, sz: u8, val: Self::Value) -> &mut Self {
+        let one = Self::Value::from(1);
+        let mask = (one << sz) - one;
+        if val & !mask != Self::Value::from(0) {
             panic!("{} is too big", val);
         }
 
@@ -39,94 +51,581 @@ pub trait Reg<T: Trait<T>> {
 
     // set_bit sets bit bitpos to val.
     fn set_bit(&mut self, bitpos: u8, val: bool) -> &mut Self {
-        self.set_bits(bitpos, 1, T::from(val))
+        self.set_bits(bitpos, 1, Self::Value::from(val))
     }
 
     // get_bits returns bits [shift .. shift+sz].
-    fn get_bits(&self, shift: u8, sz: u8) -> T {
-        let mask = (T::from(1) << sz) - T::from(1);
+    fn get_bits(&self, shift: u8, sz: u8) -> Self::Value {
+        let one = Self::Value::from(1);
+        let mask = (one << sz) - one;
         let val = self.get_value();
-        return (val >> shift) & mask;
+        (val >> shift) & mask
     }
 
     // get_bit returns bit bitpos.
     fn get_bit(&self, bitpos: u8) -> bool {
-        self.get_bits(bitpos, 1) != T::from(0)
+        self.get_bits(bitpos, 1) != Self::Value::from(0)
     }
 }
 
-/*
-#[macro_export]
-macro_rules! define_bit {
-    ($bitpos:expr, $name:ident) => {
-        pub fn concat_idents!(get_, $name)(&self) -> bool {
-            self.get_bit($bitpos)
+// RegRead is a register that can be read.
+pub trait RegRead: Reg {
+    // fetch reads the hardware register and returns it.
+    fn fetch(&mut self) -> &mut Self;
+
+    // read fetches the register and returns what f makes of it.
+    fn read<R>(&mut self, f: impl FnOnce(&Self) -> R) -> R {
+        f(self.fetch())
+    }
+
+    // wait_until fetches the register until f is true of it.
+    fn wait_until(&mut self, f: impl Fn(&Self) -> bool) -> &mut Self {
+        while !f(self.fetch()) {
+            core::hint::spin_loop();
         }
-        pub fn concat_idents!(set_, $name)(&mut self, val: bool) -> &mut Self {
-            self.set_bit($bitpos, val)
+        self
+    }
+
+    // wait_until_timeout is wait_until, giving up after timeout.
+    #[allow(dead_code)]
+    fn wait_until_timeout(
+        &mut self,
+        f: impl Fn(&Self) -> bool,
+        timeout: Duration,
+    ) -> Result<&mut Self, Timeout> {
+        let end = time::now() + timeout;
+        loop {
+            if f(self.fetch()) {
+                return Ok(self);
+            }
+            if time::now() >= end {
+                return Err(Timeout);
+            }
+            core::hint::spin_loop();
         }
-    };
+    }
 }
-*/
 
+// Timeout is the error from wait_until_timeout.
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Timeout;
+
+// RegWrite is a register that can be written.
+pub trait RegWrite: Reg {
+    // store writes the current value into the hardware register.
+    fn store(&self);
+
+    // write stores the value f sets up, starting from zero, so any
+    // field f doesn't set is written as zero.
+    fn write(&mut self, f: impl FnOnce(&mut Self) -> &mut Self) -> &mut Self {
+        self.set_value(Self::Value::from(0u8));
+        f(self);
+        self.store();
+        self
+    }
+
+    // modify fetches the register, changes it with f and stores it
+    // back. Write-only and write-1-to-clear fields are stored as zero
+    // unless f sets them.
+    fn modify(&mut self, f: impl FnOnce(&mut Self) -> &mut Self) -> &mut Self
+    where
+        Self: RegRead + RegFields,
+    {
+        self.fetch();
+        self.rmw_clear();
+        f(self);
+        self.store();
+        self
+    }
+}
+
+// RegFields is implemented by reg_fields! for registers with named fields.
+// Registers without fields can use reg_fields!(Reg {}) to get modify.
+pub trait RegFields: Reg {
+    // RMW_MASK has the bits a read-modify-write must store as zero:
+    // write-only fields, which don't read back what was written, and
+    // write-1-to-clear fields, which would be cleared by writing back
+    // what was read.
+    const RMW_MASK: Self::Value;
+
+    // rmw_clear zeros the RMW_MASK bits of the cached value.
+    fn rmw_clear(&mut self) -> &mut Self {
+        let val = self.get_value() & !Self::RMW_MASK;
+        self.set_value(val)
+    }
+}
+
+// assert_readable and assert_writable fail to compile unless R can be
+// fetched or stored. reg_fields! uses them to check each field's access
+// against the register's.
+#[doc(hidden)]
+pub const fn assert_readable<R: RegRead>() {}
+
+#[doc(hidden)]
+pub const fn assert_writable<R: RegWrite>() {}
+
+// ReadWrite, ReadOnly and WriteOnly are the access modes of a whole
+// register, for registers like Mmio that take it as a type parameter.
+// The mmio_reg32!, mmio_reg32_array! and cpu_reg64! macros take it as
+// a trailing rw, ro or wo, which defaults to rw.
+pub struct ReadWrite;
+pub struct ReadOnly;
+pub struct WriteOnly;
+
+// Readable and Writable are the access modes that allow fetch and store.
+pub trait Readable {}
+pub trait Writable {}
+
+impl Readable for ReadWrite {}
+impl Writable for ReadWrite {}
+impl Readable for ReadOnly {}
+impl Writable for WriteOnly {}
+
+// access! turns an rw, ro or wo access mode into its type.
+#[doc(hidden)]
 #[macro_export]
-macro_rules! define_bit_ro {
-    ($bitpos:expr, $getname:ident) => {
-        #[allow(dead_code)]
-        pub fn $getname(&self) -> bool {
-            self.get_bit($bitpos)
-        }
+macro_rules! access {
+    (rw) => {
+        $crate::reg::ReadWrite
+    };
+    (ro) => {
+        $crate::reg::ReadOnly
+    };
+    (wo) => {
+        $crate::reg::WriteOnly
     };
 }
 
-#[macro_export]
-macro_rules! define_bit_wo {
-    ($bitpos:expr, $setname:ident) => {
-        #[allow(dead_code)]
-        pub fn $setname(&mut self, val: bool) -> &mut Self {
-            self.set_bit($bitpos, val)
+// Hex formats a register or field value in hex for Debug.
+#[doc(hidden)]
+pub struct Hex<T>(pub T);
+
+impl<T: fmt::LowerHex> fmt::Debug for Hex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#x}", self.0)
+    }
+}
+
+// EnumValue formats an enum field for Debug, as the variant, or the
+// raw value in hex if no variant matches.
+#[doc(hidden)]
+pub struct EnumValue<E, T>(pub Result<E, T>);
+
+impl<E: fmt::Debug, T: fmt::LowerHex> fmt::Debug for EnumValue<E, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.0 {
+            Ok(e) => e.fmt(f),
+            Err(v) => write!(f, "{:#x}", v),
         }
-    };
+    }
+}
+
+// Dump is something dump! can print: a readable register with fields,
+// or a register_block! peripheral.
+#[allow(dead_code)]
+pub trait Dump {
+    fn dump(self);
+}
+
+impl<R: RegRead + fmt::Debug> Dump for R {
+    fn dump(mut self) {
+        crate::print!("{:?}\n", self.fetch());
+    }
 }
 
-// TODO: ideally we could generate $setname and $getname
-// with concat_ident! from a single name.
+// dump! fetches registers and prints them, field by field, on the
+// console. Given a Block it prints all of the peripheral's readable
+// registers:
+//
+//   dump!(AUX);
+//   dump!(UartFr::zero(), UartCr::zero());
 #[macro_export]
-macro_rules! define_bit {
-    ($bitpos:expr, $setname:ident, $getname:ident) => {
-        $crate::define_bit_wo!($bitpos, $setname);
-        $crate::define_bit_ro!($bitpos, $getname);
+macro_rules! dump {
+    ($($reg:expr),+ $(,)?) => {
+        $( $crate::reg::Dump::dump($reg); )+
     };
 }
 
+// reg_fields! declares the fields of a register, each by name, access
+// and bits, where the bits are a single bit or a lo..hi range, and
+// optionally a field_enum! type for the field's values:
+//
+//   reg_fields!(AuxMuLcr {
+//       data_size: rw @ 0..2 as DataSize,
+//       break_enb: rw @ 6,
+//   });
+//
+// For a field x it generates x() to read it from the cached value,
+// set_x(val) to change the cached value, and modify_x(val) as a
+// shorthand for modify(|r| r.set_x(val)). Single bit fields are
+// bools, ranges are the register's Value type, and enum fields take
+// the enum and read back as Result<enum, raw value>. Access is rw,
+// ro (x() only), wo (set_x() only) or w1c, for status bits that are
+// cleared by writing 1. modify_x exists for rw and w1c fields, and
+// stores zero to every wo and w1c field other than x, so it never
+// clears a pending status bit or triggers a write-only action it
+// wasn't asked to. A field's access is checked at compile time against
+// the register's: ro fields need a readable register, wo fields a
+// writable one, and rw and w1c fields both.
+//
+// It also implements Debug, showing the cached value and every field
+// that can be read, like "AuxMuLsr { value: 0x60, data_ready: false,
+// tx_empty: true }". Registers without fields can use reg_fields!(Reg {})
+// to get it.
 #[macro_export]
-macro_rules! define_bits_ro {
-    ($bitpos:expr, $width:expr, $eltype:ty, $getname:ident) => {
+macro_rules! reg_fields {
+    ($reg:ty {
+        $($name:ident : $access:ident @ $lo:literal $(.. $hi:literal)? $(as $enum:ident)?),* $(,)?
+    }) => {
         #[allow(dead_code)]
-        pub fn $getname(&self) -> $eltype {
-            self.get_bits($bitpos, $width)
+        impl $reg {
+            $( $crate::reg_field!($access, $name, $lo $(, $hi)? $(; $enum)?); )*
+        }
+
+        impl $crate::reg::RegFields for $reg {
+            const RMW_MASK: <Self as $crate::reg::Reg>::Value =
+                0 $(| $crate::reg_field!(@rmw $access, $lo $(, $hi)?))*;
         }
+
+        impl core::fmt::Debug for $reg {
+            fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+                let reg = self;
+                let mut d = f.debug_struct(stringify!($reg));
+                d.field("value", &$crate::reg::Hex($crate::reg::Reg::get_value(reg)));
+                $( $crate::reg_field!(@debug d, reg, $access, $name, $lo $(, $hi)? $(; $enum)?); )*
+                d.finish()
+            }
+        }
+
+        $( $crate::reg_field!(@check $lo $(, $hi)? $(; $enum)?); )*
+        $( $crate::reg_field!(@access $reg, $access); )*
     };
 }
 
+#[doc(hidden)]
 #[macro_export]
-macro_rules! define_bits_wo {
-    ($bitpos:expr, $width:expr, $eltype:ty, $setname:ident) => {
-        #[allow(dead_code)]
-        pub fn $setname(&mut self, val: $eltype) -> &mut Self {
-            self.set_bits($bitpos, $width, val)
+macro_rules! reg_field {
+    (ro, $name:ident, $bit:literal) => {
+        pub fn $name(&self) -> bool {
+            $crate::reg::Reg::get_bit(self, $bit)
+        }
+    };
+    (wo, $name:ident, $bit:literal) => {
+        paste::paste! {
+            pub fn [<set_ $name>](&mut self, val: bool) -> &mut Self {
+                $crate::reg::Reg::set_bit(self, $bit, val)
+            }
+        }
+    };
+    (ro, $name:ident, $lo:literal, $hi:literal) => {
+        pub fn $name(&self) -> <Self as $crate::reg::Reg>::Value {
+            $crate::reg::Reg::get_bits(self, $lo, $hi - $lo)
+        }
+    };
+    (wo, $name:ident, $lo:literal, $hi:literal) => {
+        paste::paste! {
+            pub fn [<set_ $name>](&mut self, val: <Self as $crate::reg::Reg>::Value) -> &mut Self {
+                $crate::reg::Reg::set_bits(self, $lo, $hi - $lo, val)
+            }
+        }
+    };
+    (ro, $name:ident, $lo:literal $(, $hi:literal)?; $enum:ident) => {
+        pub fn $name(&self) -> Result<$enum, <Self as $crate::reg::Reg>::Value> {
+            let width = $crate::reg_field!(@width $lo $(, $hi)?);
+            core::convert::TryFrom::try_from($crate::reg::Reg::get_bits(self, $lo, width))
+        }
+    };
+    (wo, $name:ident, $lo:literal $(, $hi:literal)?; $enum:ident) => {
+        paste::paste! {
+            pub fn [<set_ $name>](&mut self, val: $enum) -> &mut Self {
+                let width = $crate::reg_field!(@width $lo $(, $hi)?);
+                $crate::reg::Reg::set_bits(self, $lo, width, val.into())
+            }
+        }
+    };
+    (rw, $name:ident, $lo:literal $(, $hi:literal)? $(; $enum:ident)?) => {
+        $crate::reg_field!(ro, $name, $lo $(, $hi)? $(; $enum)?);
+        $crate::reg_field!(wo, $name, $lo $(, $hi)? $(; $enum)?);
+        paste::paste! {
+            pub fn [<modify_ $name>](
+                &mut self,
+                val: $crate::reg_field!(@type $lo $(, $hi)? $(; $enum)?),
+            ) -> &mut Self {
+                $crate::reg::RegWrite::modify(self, |r| r.[<set_ $name>](val))
+            }
         }
     };
+    (w1c, $name:ident, $lo:literal $(, $hi:literal)? $(; $enum:ident)?) => {
+        $crate::reg_field!(rw, $name, $lo $(, $hi)? $(; $enum)?);
+    };
+    (@type $lo:literal $(, $hi:literal)?; $enum:ident) => { $enum };
+    (@type $bit:literal) => { bool };
+    (@type $lo:literal, $hi:literal) => { <Self as $crate::reg::Reg>::Value };
+    // Every value of an enum field must fit in the field.
+    (@check $lo:literal $(, $hi:literal)?; $enum:ident) => {
+        const _: () = assert!($enum::MAX >> $crate::reg_field!(@width $lo $(, $hi)?) == 0);
+    };
+    (@check $lo:literal $(, $hi:literal)?) => {};
+    // The register must support the field's access.
+    (@access $reg:ty, ro) => {
+        const _: () = $crate::reg::assert_readable::<$reg>();
+    };
+    (@access $reg:ty, wo) => {
+        const _: () = $crate::reg::assert_writable::<$reg>();
+    };
+    (@access $reg:ty, $access:ident) => {
+        const _: () = $crate::reg::assert_readable::<$reg>();
+        const _: () = $crate::reg::assert_writable::<$reg>();
+    };
+    (@debug $d:ident, $reg:ident, wo, $name:ident, $($bits:tt)*) => {};
+    (@debug $d:ident, $reg:ident, $access:ident, $name:ident, $lo:literal $(, $hi:literal)?; $enum:ident) => {
+        $d.field(stringify!($name), &$crate::reg::EnumValue($reg.$name()));
+    };
+    (@debug $d:ident, $reg:ident, $access:ident, $name:ident, $bit:literal) => {
+        $d.field(stringify!($name), &$reg.$name());
+    };
+    (@debug $d:ident, $reg:ident, $access:ident, $name:ident, $lo:literal, $hi:literal) => {
+        $d.field(stringify!($name), &$crate::reg::Hex($reg.$name()));
+    };
+    (@rmw wo, $lo:literal $(, $hi:literal)?) => { $crate::reg_field!(@mask $lo $(, $hi)?) };
+    (@rmw w1c, $lo:literal $(, $hi:literal)?) => { $crate::reg_field!(@mask $lo $(, $hi)?) };
+    (@rmw $access:ident, $lo:literal $(, $hi:literal)?) => { 0 };
+    (@mask $bit:literal) => { 1 << $bit };
+    (@mask $lo:literal, $hi:literal) => {
+        !0 >> (core::mem::size_of::<<Self as $crate::reg::Reg>::Value>() * 8 - ($hi - $lo)) << $lo
+    };
+    (@width $bit:literal) => { 1 };
+    (@width $lo:literal, $hi:literal) => { $hi - $lo };
 }
 
-// TODO: ideally we could generate $setname and $getname
-// with concat_ident! from a single name.
-// TODO: cant we do something with an associated type or
-// with type inference so that we dont need to specify $eltype!?
+// field_enum! declares an enum of the values a register field can
+// hold, for use with "as" in reg_fields!. Each variant has an explicit
+// value. It converts into the register types with From and back with
+// TryFrom, which fails with the raw value if no variant matches.
+//
+//   field_enum!(
+//       pub enum DataSize {
+//           Seven = 0,
+//           Eight = 3,
+//       }
+//   );
 #[macro_export]
-macro_rules! define_bits {
-    ($bitpos:expr, $width:expr, $eltype:ty, $setname:ident, $getname:ident) => {
-        $crate::define_bits_wo!($bitpos, $width, $eltype, $setname);
-        $crate::define_bits_ro!($bitpos, $width, $eltype, $getname);
+macro_rules! field_enum {
+    (
+        $(#[$meta:meta])*
+        $vis:vis enum $name:ident {
+            $($(#[$vmeta:meta])* $variant:ident = $val:literal),* $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        #[allow(dead_code)]
+        #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+        $vis enum $name {
+            $($(#[$vmeta])* $variant = $val),*
+        }
+
+        impl $name {
+            // MAX is the largest value of any variant.
+            pub const MAX: u64 = {
+                let vals: &[u64] = &[$($val),*];
+                let mut max = 0;
+                let mut i = 0;
+                while i < vals.len() {
+                    if vals[i] > max {
+                        max = vals[i];
+                    }
+                    i += 1;
+                }
+                max
+            };
+        }
+
+        $crate::field_enum!(@conv $name, u32, $($variant = $val),*);
+        $crate::field_enum!(@conv $name, u64, $($variant = $val),*);
     };
+    (@conv $name:ident, $t:ty, $($variant:ident = $val:literal),*) => {
+        impl From<$name> for $t {
+            fn from(val: $name) -> $t {
+                val as $t
+            }
+        }
+
+        impl core::convert::TryFrom<$t> for $name {
+            type Error = $t;
+
+            fn try_from(val: $t) -> Result<Self, $t> {
+                match val {
+                    $($val => Ok($name::$variant),)*
+                    _ => Err(val),
+                }
+            }
+        }
+    };
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::{Reg, RegRead, RegWrite, Timeout};
+    use crate::mmio_reg32;
+    use crate::mock::{self, Access, Loc};
+    use crate::time::Duration;
+
+    const ADDR: usize = 0x1000;
+
+    mmio_reg32!(TestReg, ADDR);
+
+    field_enum!(
+        enum Mode {
+            Off = 0,
+            Slow = 1,
+            Fast = 3,
+        }
+    );
+
+    reg_fields!(TestReg {
+        a: rw @ 0,
+        mode: rw @ 1..3 as Mode,
+        b: rw @ 4..7,
+        status: ro @ 8,
+        clear: wo @ 9,
+        pending: w1c @ 10,
+        top: rw @ 31,
+    });
+
+    #[test]
+    fn fields_pack_into_value() {
+        let mut r = TestReg::zero();
+        r.set_a(true).set_b(5).set_top(true);
+        assert_eq!(r.get_value(), 1 | 5 << 4 | 1 << 31);
+        assert!(r.a());
+        assert_eq!(r.b(), 5);
+        assert!(r.top());
+    }
+
+    #[test]
+    fn set_bits_keeps_other_bits() {
+        let mut r = TestReg::new(0xffff_ffff);
+        r.set_b(0).set_a(false);
+        assert_eq!(r.get_value(), 0xffff_ff8e);
+        assert!(!r.a());
+        assert!(r.status());
+        assert_eq!(r.get_bits(8, 8), 0xff);
+    }
+
+    #[test]
+    #[should_panic]
+    fn set_bits_rejects_wide_values() {
+        TestReg::zero().set_b(8);
+    }
+
+    #[test]
+    fn fetch_and_store_access_the_register() {
+        mock::reset();
+        mock::preset(Loc::Mmio(ADDR), 0x30);
+        TestReg::fetch().set_a(true).store();
+        assert_eq!(
+            mock::take_log(),
+            [
+                Access::Read(Loc::Mmio(ADDR), 0x30),
+                Access::Write(Loc::Mmio(ADDR), 0x31)
+            ]
+        );
+    }
+
+    #[test]
+    fn modify_fetches_and_stores() {
+        mock::reset();
+        mock::preset(Loc::Mmio(ADDR), 0x100);
+        let mut r = TestReg::zero();
+        r.modify_b(3);
+        assert_eq!(r.get_value(), 0x130);
+        assert_eq!(
+            mock::take_log(),
+            [
+                Access::Read(Loc::Mmio(ADDR), 0x100),
+                Access::Write(Loc::Mmio(ADDR), 0x130)
+            ]
+        );
+        TestReg::zero().set_clear(true).store();
+        assert_eq!(mock::value(Loc::Mmio(ADDR)), 0x200);
+    }
+
+    #[test]
+    fn modify_zeros_wo_and_w1c_fields() {
+        mock::reset();
+        mock::preset(Loc::Mmio(ADDR), 0x700);
+        TestReg::zero().modify_a(true);
+        assert_eq!(mock::value(Loc::Mmio(ADDR)), 0x101);
+
+        mock::preset(Loc::Mmio(ADDR), 0x500);
+        let mut r = TestReg::zero();
+        r.modify_pending(true);
+        assert_eq!(mock::value(Loc::Mmio(ADDR)), 0x500);
+        assert!(r.pending());
+    }
+
+    #[test]
+    fn closures_read_write_and_modify() {
+        mock::reset();
+        mock::preset(Loc::Mmio(ADDR), 0x141);
+        assert_eq!(TestReg::zero().read(|r| r.b()), 4);
+        TestReg::new(0xff).write(|w| w.set_mode(Mode::Slow));
+        assert_eq!(mock::value(Loc::Mmio(ADDR)), 1 << 1);
+        TestReg::zero().modify(|r| r.set_a(true).set_b(7));
+        assert_eq!(mock::value(Loc::Mmio(ADDR)), 1 | 1 << 1 | 7 << 4);
+    }
+
+    #[test]
+    fn wait_until_polls() {
+        mock::reset();
+        mock::preset_after(Loc::Mmio(ADDR), 3, 1 << 8);
+        assert!(TestReg::wait_until(|r| r.status()).status());
+        assert_eq!(fetches(), 4);
+
+        mock::preset(Loc::Sys("CNTFRQ_EL0"), 1_000_000);
+        mock::preset(Loc::Mmio(ADDR), 0);
+        mock::preset_after(Loc::Mmio(ADDR), 2, 1);
+        let r = TestReg::zero()
+            .wait_until_timeout(|r| r.a(), Duration::from_millis(1))
+            .map(|r| r.a());
+        assert_eq!(r, Ok(true));
+        assert_eq!(fetches(), 3);
+
+        let r = TestReg::zero()
+            .wait_until_timeout(|r| r.status(), Duration::ZERO)
+            .map(|_| ());
+        assert_eq!(r, Err(Timeout));
+    }
+
+    // fetches returns how many times TestReg was read since the last call.
+    fn fetches() -> usize {
+        mock::take_log()
+            .iter()
+            .filter(|a| matches!(a, Access::Read(Loc::Mmio(ADDR), _)))
+            .count()
+    }
+
+    #[test]
+    fn debug_shows_readable_fields() {
+        let r = TestReg::new(1 | 3 << 1 | 5 << 4 | 1 << 8 | 1 << 9 | 1 << 10);
+        assert_eq!(
+            format!("{:?}", r),
+            "TestReg { value: 0x757, a: true, mode: Fast, b: 0x5, status: true, \
+             pending: true, top: false }"
+        );
+        let r = TestReg::new(2 << 1);
+        assert!(format!("{:?}", r).contains("mode: 0x2,"));
+    }
+
+    #[test]
+    fn enum_fields_convert() {
+        let mut r = TestReg::zero();
+        r.set_mode(Mode::Fast);
+        assert_eq!(r.get_value(), 3 << 1);
+        assert_eq!(r.mode(), Ok(Mode::Fast));
+        r.set_value(2 << 1);
+        assert_eq!(r.mode(), Err(2));
+        assert_eq!(Mode::MAX, 3);
+    }
 }
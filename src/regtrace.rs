@@ -0,0 +1,238 @@
+/*
+ * regtrace.rs
+ * MMIO register access tracing, for bringing up drivers.
+ *
+ * Built with --features trace-regs, every fetch and store through an
+ * mmio_reg!, register_block! or Mmio register is logged to the memory
+ * log ring (see log::dmesg) with the register's name, its address and
+ * the value before and after. Only the ring is written, since the
+ * console's own registers are traced too.
+ *
+ * Each peripheral has a bit in the enable mask, which starts out as
+ * board::TRACE_REGS and can be changed with set_mask.
+ *
+ * The old value is the last one traced at that address, not something
+ * read from the hardware before a store, so it is "?" the first time an
+ * address is seen, or once LAST is full.
+ */
+
+use crate::{board, cpu, log};
+use core::sync::atomic::{AtomicU32, Ordering};
+use spin::Mutex;
+
+// Peripheral bits in the enable mask.
+#[allow(dead_code)]
+pub const AUX: u32 = 1 << 0;
+#[allow(dead_code)]
+pub const GPIO: u32 = 1 << 1;
+#[allow(dead_code)]
+pub const INTC: u32 = 1 << 2;
+#[allow(dead_code)]
+pub const SYSTIMER: u32 = 1 << 3;
+#[allow(dead_code)]
+pub const PL011: u32 = 1 << 4;
+#[allow(dead_code)]
+pub const LOCAL: u32 = 1 << 5;
+// OTHER is any address not in PERIPHERALS.
+#[allow(dead_code)]
+pub const OTHER: u32 = 1 << 31;
+#[allow(dead_code)]
+pub const ALL: u32 = !0;
+
+// PERIPHERALS are the traced address ranges, as (name, base, len, bit).
+const PERIPHERALS: [(&str, usize, usize, u32); 6] = [
+    ("aux", board::AUX_BASE, 0x100, AUX),
+    ("gpio", board::GPIO_BASE, 0x100, GPIO),
+    ("intc", board::INTC_BASE, 0x400, INTC),
+    ("systimer", board::SYSTIMER_BASE, 0x20, SYSTIMER),
+    ("pl011", board::PL011_BASE, 0x100, PL011),
+    ("local", board::LOCAL_BASE, 0x100, LOCAL),
+];
+
+#[cfg(not(test))]
+static MASK: AtomicU32 = AtomicU32::new(board::TRACE_REGS);
+
+// In host tests every register access reaches record, so each test
+// thread has its own mask, starting empty, and only tests that set it
+// are traced.
+#[cfg(test)]
+std::thread_local! {
+    static MASK: AtomicU32 = const { AtomicU32::new(0) };
+}
+
+// with_mask calls f with the enable mask.
+#[cfg(not(test))]
+fn with_mask<R>(f: impl FnOnce(&AtomicU32) -> R) -> R {
+    f(&MASK)
+}
+
+#[cfg(test)]
+fn with_mask<R>(f: impl FnOnce(&AtomicU32) -> R) -> R {
+    MASK.with(f)
+}
+
+// set_mask sets which peripherals are traced.
+#[allow(dead_code)]
+pub fn set_mask(mask: u32) {
+    with_mask(|m| m.store(mask, Ordering::Relaxed));
+}
+
+// mask returns which peripherals are traced.
+#[allow(dead_code)]
+pub fn mask() -> u32 {
+    with_mask(|m| m.load(Ordering::Relaxed))
+}
+
+// peripheral returns the name and mask bit of the peripheral at addr.
+fn peripheral(addr: usize) -> (&'static str, u32) {
+    for &(name, base, len, bit) in PERIPHERALS.iter() {
+        if addr >= base && addr < base + len {
+            return (name, bit);
+        }
+    }
+    ("mmio", OTHER)
+}
+
+// LAST_SIZE is the number of registers whose last value is remembered.
+const LAST_SIZE: usize = 256;
+
+// LAST holds the last value seen at each traced address, as (addr, value)
+// in an open-addressed table. An addr of 0 is a free slot. When the table
+// is full, old values are no longer known.
+static LAST: Mutex<[(usize, u64); LAST_SIZE]> = Mutex::new([(0, 0); LAST_SIZE]);
+
+// swap_last remembers val as the value at addr in last and returns the
+// old one.
+fn swap_last(last: &mut [(usize, u64); LAST_SIZE], addr: usize, val: u64) -> Option<u64> {
+    let start = (addr >> 2) % LAST_SIZE;
+    for i in 0..LAST_SIZE {
+        let slot = &mut last[(start + i) % LAST_SIZE];
+        if slot.0 == addr {
+            let old = slot.1;
+            slot.1 = val;
+            return Some(old);
+        }
+        if slot.0 == 0 {
+            *slot = (addr, val);
+            return None;
+        }
+    }
+    None
+}
+
+// Old formats the previous value of a register, which may be unknown.
+struct Old(Option<u64>);
+
+impl core::fmt::Display for Old {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self.0 {
+            Some(val) => write!(f, "{:#x}", val),
+            None => write!(f, "?"),
+        }
+    }
+}
+
+// record logs a fetch or store of val at register name at addr,
+// if its peripheral is enabled in the mask.
+pub fn record(name: &str, addr: usize, store: bool, val: u64) {
+    let (periph, bit) = peripheral(addr);
+    if mask() & bit == 0 {
+        return;
+    }
+    cpu::without_irqs(|| {
+        let old = Old(swap_last(&mut LAST.lock(), addr, val));
+        let op = if store { "store" } else { "fetch" };
+        log::log_to_ring(
+            log::Level::Trace,
+            module_path!(),
+            format_args!(
+                "{} {} {:#x} {} {} -> {:#x}",
+                periph, name, addr, op, old, val
+            ),
+        );
+    });
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+    use crate::mmio_reg32;
+    use crate::mock::{self, Loc};
+    use crate::reg::{Reg, RegWrite};
+
+    mmio_reg32!(GpPudTraced, board::GPIO_BASE + 0x94);
+
+    #[test]
+    fn addresses_map_to_their_peripheral() {
+        assert_eq!(peripheral(board::AUX_BASE + 0x54), ("aux", AUX));
+        assert_eq!(peripheral(board::GPIO_BASE + 0x94), ("gpio", GPIO));
+        assert_eq!(peripheral(board::LOCAL_BASE + 0xfc), ("local", LOCAL));
+        assert_eq!(peripheral(board::LOCAL_BASE + 0x100), ("mmio", OTHER));
+        assert_eq!(peripheral(0x1000), ("mmio", OTHER));
+    }
+
+    #[test]
+    fn swap_last_returns_the_previous_value() {
+        let mut last = [(0, 0); LAST_SIZE];
+        assert_eq!(swap_last(&mut last, 0x1000, 1), None);
+        assert_eq!(swap_last(&mut last, 0x1000, 2), Some(1));
+        // 0x1400 hashes to the same slot as 0x1000.
+        assert_eq!(swap_last(&mut last, 0x1400, 3), None);
+        assert_eq!(swap_last(&mut last, 0x1400, 4), Some(3));
+        assert_eq!(swap_last(&mut last, 0x1000, 5), Some(2));
+    }
+
+    #[test]
+    fn swap_last_forgets_new_addresses_when_full() {
+        let mut last = [(0, 0); LAST_SIZE];
+        for i in 0..LAST_SIZE {
+            assert_eq!(swap_last(&mut last, 0x1000 + 4 * i, 1), None);
+        }
+        let extra = 0x1000 + 4 * LAST_SIZE;
+        assert_eq!(swap_last(&mut last, extra, 2), None);
+        assert_eq!(swap_last(&mut last, extra, 3), None);
+        assert_eq!(swap_last(&mut last, 0x1000, 4), Some(1));
+    }
+
+    #[test]
+    fn record_logs_old_and_new_values() {
+        let addr = board::GPIO_BASE + 0x98;
+        mock::reset();
+        mock::preset(Loc::Sys("CNTFRQ_EL0"), 1_000_000);
+        set_mask(GPIO);
+        record("GpPupdClk", addr, true, 0x4000);
+        record("GpPupdClk", addr, true, 0);
+        let mut lines = Vec::new();
+        log::MEMORY.for_each_line(|line| {
+            let line = core::str::from_utf8(line).unwrap();
+            if line.contains("GpPupdClk") {
+                lines.push(line.split(": ").nth(1).unwrap().to_string());
+            }
+        });
+        assert_eq!(
+            lines,
+            [
+                "gpio GpPupdClk 0x3f200098 store ? -> 0x4000\n",
+                "gpio GpPupdClk 0x3f200098 store 0x4000 -> 0x0\n",
+            ]
+        );
+    }
+
+    #[test]
+    fn mmio_reg_stores_land_in_the_ring() {
+        mock::reset();
+        mock::preset(Loc::Sys("CNTFRQ_EL0"), 1_000_000);
+        set_mask(GPIO);
+        GpPudTraced::new(2).store();
+        set_mask(0);
+        GpPudTraced::new(1).store();
+        let mut lines = Vec::new();
+        log::MEMORY.for_each_line(|line| {
+            let line = core::str::from_utf8(line).unwrap();
+            if line.contains("GpPudTraced") {
+                lines.push(line.split(": ").nth(1).unwrap().to_string());
+            }
+        });
+        assert_eq!(lines, ["gpio GpPudTraced 0x3f200094 store ? -> 0x2\n"]);
+    }
+}
@@ -0,0 +1,105 @@
+/*
+ * ringbuf.rs
+ * Lock-free single producer, single consumer byte ring buffer.
+ *
+ * One side (typically an IRQ handler) pushes and the other pops,
+ * without either ever taking a lock. Callers with more than one
+ * producer or consumer must serialize that side themselves.
+ */
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+// RingBuffer holds up to N bytes. N must be a power of two.
+pub struct RingBuffer<const N: usize> {
+    buf: UnsafeCell<[u8; N]>,
+    // head and tail count bytes pushed and popped, and wrap freely.
+    head: AtomicUsize,
+    tail: AtomicUsize,
+}
+
+// Each byte slot is only written by the producer while it is free
+// and only read by the consumer while it is full.
+unsafe impl<const N: usize> Sync for RingBuffer<N> {}
+
+#[allow(dead_code)]
+impl<const N: usize> RingBuffer<N> {
+    pub const fn new() -> Self {
+        assert!(N.is_power_of_two());
+        RingBuffer {
+            buf: UnsafeCell::new([0; N]),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    // push adds ch, returning false if the buffer is full.
+    pub fn push(&self, ch: u8) -> bool {
+        let head = self.head.load(Ordering::Relaxed);
+        let tail = self.tail.load(Ordering::Acquire);
+        if head.wrapping_sub(tail) == N {
+            return false;
+        }
+        unsafe { (*self.buf.get())[head % N] = ch };
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+        true
+    }
+
+    // pop removes the oldest byte, if there is one.
+    pub fn pop(&self) -> Option<u8> {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let head = self.head.load(Ordering::Acquire);
+        if head == tail {
+            return None;
+        }
+        let ch = unsafe { (*self.buf.get())[tail % N] };
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        Some(ch)
+    }
+
+    pub fn len(&self) -> usize {
+        let head = self.head.load(Ordering::Acquire);
+        let tail = self.tail.load(Ordering::Acquire);
+        head.wrapping_sub(tail)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len() == N
+    }
+}
+
+#[cfg(all(test, target_os = "none"))]
+mod tests {
+    use super::RingBuffer;
+
+    #[test_case]
+    fn push_pop_in_order() {
+        let rb = RingBuffer::<4>::new();
+        assert!(rb.is_empty());
+        assert!(rb.push(1) && rb.push(2) && rb.push(3));
+        assert_eq!(rb.len(), 3);
+        assert_eq!(rb.pop(), Some(1));
+        assert_eq!(rb.pop(), Some(2));
+        assert_eq!(rb.pop(), Some(3));
+        assert_eq!(rb.pop(), None);
+    }
+
+    #[test_case]
+    fn full_and_wrap() {
+        let rb = RingBuffer::<4>::new();
+        for round in 0..3u8 {
+            for i in 0..4 {
+                assert!(rb.push(round * 4 + i));
+            }
+            assert!(rb.is_full());
+            assert!(!rb.push(0xff));
+            for i in 0..4 {
+                assert_eq!(rb.pop(), Some(round * 4 + i));
+            }
+        }
+    }
+}
@@ -0,0 +1,242 @@
+/*
+ * semihosting.rs
+ * ARM semihosting client.
+ *
+ * Semihosting lets the guest ask the debugger (or qemu, when run with
+ * -semihosting) to do I/O on its behalf. A call puts the operation in
+ * w0 and a pointer to its parameter block in x1, and executes
+ * "hlt #0xF000". The result comes back in x0.
+ * Ref: ARM Semihosting for AArch32 and AArch64, version 3.
+ */
+
+#[cfg(target_os = "none")]
+use core::arch::asm;
+
+const SYS_OPEN: u32 = 0x01;
+const SYS_CLOSE: u32 = 0x02;
+const SYS_WRITEC: u32 = 0x03;
+const SYS_WRITE0: u32 = 0x04;
+const SYS_WRITE: u32 = 0x05;
+const SYS_READ: u32 = 0x06;
+const SYS_SEEK: u32 = 0x0a;
+const SYS_FLEN: u32 = 0x0c;
+const SYS_CLOCK: u32 = 0x10;
+const SYS_TIME: u32 = 0x11;
+const SYS_ERRNO: u32 = 0x13;
+const SYS_GET_CMDLINE: u32 = 0x15;
+const SYS_EXIT: u32 = 0x18;
+const SYS_EXIT_EXTENDED: u32 = 0x20;
+const SYS_ELAPSED: u32 = 0x30;
+const SYS_TICKFREQ: u32 = 0x31;
+
+// ADP_Stopped_ApplicationExit is the SYS_EXIT reason for a normal exit.
+const ADP_STOPPED_APPLICATION_EXIT: u64 = 0x20026;
+
+// MAX_PATH is the longest file name open accepts.
+const MAX_PATH: usize = 256;
+
+// call makes semihosting call op with parameter block arg.
+//
+// Safety: arg must point to whatever op expects, and any buffers it
+// names must stay valid for the call.
+#[cfg(target_os = "none")]
+unsafe fn call(op: u32, arg: usize) -> i64 {
+    let ret: i64;
+    asm!(
+        "hlt #0xF000",
+        in("w0") op,
+        in("x1") arg,
+        lateout("x0") ret,
+    );
+    ret
+}
+
+// There is no semihosting host when running as a host test.
+#[cfg(not(target_os = "none"))]
+unsafe fn call(op: u32, _arg: usize) -> i64 {
+    panic!("semihosting call {:#x} in a host test", op);
+}
+
+// Error is a failed call, with the host's errno.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Error {
+    pub errno: i64,
+}
+
+// last_error returns the error from the last failed call.
+fn last_error() -> Error {
+    Error {
+        errno: unsafe { call(SYS_ERRNO, 0) },
+    }
+}
+
+// check turns a -1 result into the host's error.
+fn check(ret: i64) -> Result<u64, Error> {
+    if ret == -1 {
+        Err(last_error())
+    } else {
+        Ok(ret as u64)
+    }
+}
+
+// write_c writes one character to the debug console.
+pub fn write_c(ch: u8) {
+    unsafe { call(SYS_WRITEC, &ch as *const u8 as usize) };
+}
+
+// write_str writes s to the debug console.
+#[allow(dead_code)]
+pub fn write_str(s: &str) {
+    // SYS_WRITE0 needs a NUL terminated string, so go in chunks.
+    let mut buf = [0u8; 128];
+    for chunk in s.as_bytes().chunks(buf.len() - 1) {
+        buf[..chunk.len()].copy_from_slice(chunk);
+        buf[chunk.len()] = 0;
+        unsafe { call(SYS_WRITE0, buf.as_ptr() as usize) };
+    }
+}
+
+// OpenMode is how open opens a file, like fopen's mode argument.
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OpenMode {
+    Read = 1,        // "rb"
+    ReadWrite = 3,   // "r+b"
+    Write = 5,       // "wb", truncating
+    WriteRead = 7,   // "w+b", truncating
+    Append = 9,      // "ab"
+    AppendRead = 11, // "a+b"
+}
+
+// File is an open host file. It is closed when dropped.
+pub struct File {
+    handle: u64,
+}
+
+#[allow(dead_code)]
+impl File {
+    // open opens the host file named path.
+    // The special name ":tt" is the debug console.
+    pub fn open(path: &str, mode: OpenMode) -> Result<File, Error> {
+        let mut name = [0u8; MAX_PATH];
+        if path.len() >= MAX_PATH || path.contains('\0') {
+            return Err(Error { errno: 22 }); // EINVAL
+        }
+        name[..path.len()].copy_from_slice(path.as_bytes());
+        let block = [name.as_ptr() as u64, mode as u64, path.len() as u64];
+        let handle = check(unsafe { call(SYS_OPEN, block.as_ptr() as usize) })?;
+        Ok(File { handle })
+    }
+
+    // write writes buf and returns the number of bytes written,
+    // which may be less than buf.len().
+    pub fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        let block = [self.handle, buf.as_ptr() as u64, buf.len() as u64];
+        // The result is the number of bytes not written.
+        let left = unsafe { call(SYS_WRITE, block.as_ptr() as usize) };
+        if left < 0 || left as usize > buf.len() {
+            return Err(last_error());
+        }
+        Ok(buf.len() - left as usize)
+    }
+
+    // read reads into buf and returns the number of bytes read,
+    // which is 0 at end of file.
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        let block = [self.handle, buf.as_mut_ptr() as u64, buf.len() as u64];
+        // The result is the number of bytes not read.
+        let left = unsafe { call(SYS_READ, block.as_ptr() as usize) };
+        if left < 0 || left as usize > buf.len() {
+            return Err(last_error());
+        }
+        Ok(buf.len() - left as usize)
+    }
+
+    // seek moves to pos bytes from the start of the file.
+    pub fn seek(&mut self, pos: u64) -> Result<(), Error> {
+        let block = [self.handle, pos];
+        match unsafe { call(SYS_SEEK, block.as_ptr() as usize) } {
+            0 => Ok(()),
+            _ => Err(last_error()),
+        }
+    }
+
+    // len returns the length of the file.
+    pub fn len(&self) -> Result<u64, Error> {
+        let block = [self.handle];
+        check(unsafe { call(SYS_FLEN, block.as_ptr() as usize) })
+    }
+
+    // close closes the file, reporting any error.
+    pub fn close(self) -> Result<(), Error> {
+        let handle = self.handle;
+        core::mem::forget(self);
+        close(handle)
+    }
+}
+
+fn close(handle: u64) -> Result<(), Error> {
+    let block = [handle];
+    check(unsafe { call(SYS_CLOSE, block.as_ptr() as usize) }).map(|_| ())
+}
+
+impl Drop for File {
+    fn drop(&mut self) {
+        let _ = close(self.handle);
+    }
+}
+
+// clock returns the centiseconds since the program started.
+#[allow(dead_code)]
+pub fn clock() -> Result<u64, Error> {
+    check(unsafe { call(SYS_CLOCK, 0) })
+}
+
+// time returns the host's time in seconds since the unix epoch.
+#[allow(dead_code)]
+pub fn time() -> u64 {
+    unsafe { call(SYS_TIME, 0) as u64 }
+}
+
+// elapsed returns the ticks since the program started.
+// tick_freq gives their rate.
+#[allow(dead_code)]
+pub fn elapsed() -> Result<u64, Error> {
+    let mut ticks = 0u64;
+    match unsafe { call(SYS_ELAPSED, &mut ticks as *mut u64 as usize) } {
+        0 => Ok(ticks),
+        _ => Err(last_error()),
+    }
+}
+
+// tick_freq returns the rate of elapsed's ticks in Hz.
+#[allow(dead_code)]
+pub fn tick_freq() -> Result<u64, Error> {
+    check(unsafe { call(SYS_TICKFREQ, 0) })
+}
+
+// get_cmdline copies the command line the program was started with
+// (qemu's -append) into buf and returns it.
+#[allow(dead_code)]
+pub fn get_cmdline(buf: &mut [u8]) -> Result<&str, Error> {
+    let mut block = [buf.as_mut_ptr() as u64, buf.len() as u64];
+    match unsafe { call(SYS_GET_CMDLINE, block.as_mut_ptr() as usize) } {
+        0 => {
+            // The host updates the length, which doesn't count the NUL.
+            let len = (block[1] as usize).min(buf.len());
+            core::str::from_utf8(&buf[..len]).map_err(|_| Error { errno: 84 }) // EILSEQ
+        }
+        _ => Err(last_error()),
+    }
+}
+
+// exit stops the program, and qemu exits with status code.
+pub fn exit(code: u32) -> ! {
+    let block = [ADP_STOPPED_APPLICATION_EXIT, code as u64];
+    unsafe {
+        call(SYS_EXIT_EXTENDED, block.as_ptr() as usize);
+        // Hosts without SYS_EXIT_EXTENDED take the same block here.
+        call(SYS_EXIT, block.as_ptr() as usize);
+    }
+    crate::asm::halt();
+}
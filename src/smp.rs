@@ -0,0 +1,87 @@
+/*
+ * smp.rs
+ * Secondary core bring-up.
+ *
+ * Cores 1..NCPU come out of _start and park in secondary_start, each
+ * watching its own slot in a spin table.  Once core 0 has finished
+ * global init it fills in the slots and wakes them with sev.
+ */
+
+// Bring-up is only started from _start_rust, which the host lacks.
+#![cfg_attr(not(target_os = "none"), allow(dead_code))]
+
+use crate::{asm, board, time};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+// Barrier blocks cores until a fixed number of them have arrived.
+pub struct Barrier {
+    total: usize,
+    count: AtomicUsize,
+    generation: AtomicUsize,
+}
+
+impl Barrier {
+    pub const fn new(total: usize) -> Self {
+        Barrier {
+            total,
+            count: AtomicUsize::new(0),
+            generation: AtomicUsize::new(0),
+        }
+    }
+
+    // wait blocks until total cores have called wait.
+    // The barrier resets itself and can be reused.
+    pub fn wait(&self) {
+        let generation = self.generation.load(Ordering::Acquire);
+        if self.count.fetch_add(1, Ordering::AcqRel) + 1 == self.total {
+            self.count.store(0, Ordering::Relaxed);
+            self.generation.fetch_add(1, Ordering::Release);
+            asm::sev();
+        } else {
+            while self.generation.load(Ordering::Acquire) == generation {
+                asm::wfe();
+            }
+        }
+    }
+}
+
+// SPIN_TABLE holds the entry point for each core, or 0 while it is parked.
+static SPIN_TABLE: [AtomicUsize; board::NCPU] = [const { AtomicUsize::new(0) }; board::NCPU];
+
+// ONLINE is passed by every core once its per-core init is done.
+static ONLINE: Barrier = Barrier::new(board::NCPU);
+
+// init_core does the per-core setup needed by every core.
+pub fn init_core() {
+    asm::init_exceptions();
+    time::init_core();
+}
+
+// start_secondaries releases cores 1..NCPU to run entry(core_id).
+// It returns once every core has finished init_core and is online.
+pub fn start_secondaries(entry: fn(u64)) {
+    for slot in SPIN_TABLE.iter().skip(1) {
+        slot.store(entry as usize, Ordering::Release);
+    }
+    asm::sev();
+    ONLINE.wait();
+}
+
+// secondary_start is where cores other than 0 go after _start.
+// They wait in the spin table until start_secondaries releases them.
+pub fn secondary_start(core: u64) -> ! {
+    let slot = &SPIN_TABLE[core as usize];
+    let entry = loop {
+        let entry = slot.load(Ordering::Acquire);
+        if entry != 0 {
+            break entry;
+        }
+        asm::wfe();
+    };
+    let entry: fn(u64) = unsafe { core::mem::transmute(entry) };
+
+    init_core();
+    ONLINE.wait();
+    entry(core);
+    asm::halt();
+}
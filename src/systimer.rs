@@ -0,0 +1,184 @@
+/*
+ * systimer.rs
+ * BCM2837 system timer support.
+ * Ref: BCM2837 ARM Peripherals, section 12.
+ *
+ * A free running 64-bit 1MHz counter with four 32-bit compare channels.
+ * Channels 0 and 2 belong to the GPU, 1 and 3 are free for alarms.
+ * A match on channel n raises GPU IRQ n.
+ */
+
+use crate::irq::{self, Source};
+use crate::reg::{Reg, RegWrite};
+use crate::{board, cpu, mmio_reg32, mmio_reg32_array, reg_fields};
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+
+mmio_reg32!(StCs, board::SYSTIMER_BASE);
+mmio_reg32!(StClo, board::SYSTIMER_BASE + 0x04, ro);
+mmio_reg32!(StChi, board::SYSTIMER_BASE + 0x08, ro);
+mmio_reg32_array!(StCompare, 4, board::SYSTIMER_BASE + 0x0c);
+
+// Each match bit is set when its channel's compare register matches
+// the counter, and cleared by writing 1.
+reg_fields!(StCs {
+    m0: w1c @ 0,
+    m1: w1c @ 1,
+    m2: w1c @ 2,
+    m3: w1c @ 3,
+});
+
+const NUM_CHANNELS: usize = 4;
+const FREE_CHANNELS: [usize; 2] = [1, 3];
+const FREE_HANDLERS: [fn(); 2] = [fire1, fire3];
+
+// An alarm set too close to now could be passed before the compare
+// register is written, and then wouldn't fire until the counter wraps.
+const MIN_ALARM_US: u32 = 10;
+
+// AlarmTable holds the id and callback of the pending alarm on each
+// compare channel.
+type AlarmTable = [Option<(u64, fn())>; NUM_CHANNELS];
+
+static ALARMS: Mutex<AlarmTable> = Mutex::new([None; NUM_CHANNELS]);
+
+// NEXT_ID is the id given to the next alarm, so a stale Alarm can't
+// cancel a later one that reused its channel.
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+// Alarm is a pending one-shot alarm.
+#[derive(Debug)]
+pub struct Alarm {
+    channel: usize,
+    id: u64,
+}
+
+#[allow(dead_code)]
+impl Alarm {
+    // cancel stops the alarm if it hasn't fired yet.
+    pub fn cancel(self) {
+        cpu::without_irqs(|| {
+            let mut alarms = ALARMS.lock();
+            if let Some((id, _)) = alarms[self.channel] {
+                if id == self.id {
+                    irq::disable(Source::Gpu(self.channel as u32));
+                    alarms[self.channel] = None;
+                }
+            }
+        });
+    }
+}
+
+pub struct SystemTimer;
+
+#[allow(dead_code)]
+impl SystemTimer {
+    // now_us returns microseconds since the counter started.
+    pub fn now_us() -> u64 {
+        loop {
+            let hi = StChi::fetch().get_value();
+            let lo = StClo::fetch().get_value();
+            if StChi::fetch().get_value() == hi {
+                return (hi as u64) << 32 | lo as u64;
+            }
+        }
+    }
+
+    // delay_us busy-waits for at least us microseconds.
+    pub fn delay_us(us: u64) {
+        let start = Self::now_us();
+        while Self::now_us() - start < us {
+            core::hint::spin_loop();
+        }
+    }
+
+    // alarm arranges for callback to be called from IRQ context after
+    // at least us microseconds. It returns None if no channel is free.
+    pub fn alarm(us: u32, callback: fn()) -> Option<Alarm> {
+        cpu::without_irqs(|| {
+            let mut alarms = ALARMS.lock();
+            let free = (0..FREE_CHANNELS.len()).find(|i| alarms[FREE_CHANNELS[*i]].is_none())?;
+            let channel = FREE_CHANNELS[free];
+            let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+            alarms[channel] = Some((id, callback));
+
+            let when = StClo::fetch()
+                .get_value()
+                .wrapping_add(us.max(MIN_ALARM_US));
+            clear_match(channel); // clear any stale match
+            StCompare::new().index(channel).set_value(when).store();
+            irq::register(Source::Gpu(channel as u32), FREE_HANDLERS[free]);
+            Some(Alarm { channel, id })
+        })
+    }
+}
+
+// clear_match clears a pending match on channel, and only that one.
+fn clear_match(channel: usize) {
+    let mut cs = StCs::zero();
+    match channel {
+        0 => cs.set_m0(true),
+        1 => cs.set_m1(true),
+        2 => cs.set_m2(true),
+        _ => cs.set_m3(true),
+    };
+    cs.store();
+}
+
+// fire acks a match on channel and runs its callback.
+fn fire(channel: usize) {
+    clear_match(channel);
+    irq::disable(Source::Gpu(channel as u32));
+    let alarm = ALARMS.lock()[channel].take();
+    if let Some((_, callback)) = alarm {
+        callback();
+    }
+}
+
+fn fire1() {
+    fire(1);
+}
+
+fn fire3() {
+    fire(3);
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+    use crate::mock;
+    use core::sync::atomic::AtomicUsize;
+
+    static FIRST: AtomicUsize = AtomicUsize::new(0);
+    static SECOND: AtomicUsize = AtomicUsize::new(0);
+
+    fn first() {
+        FIRST.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn second() {
+        SECOND.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[test]
+    fn stale_alarm_does_not_cancel_its_channels_next_alarm() {
+        mock::reset();
+        let old = SystemTimer::alarm(100, first).unwrap();
+        fire(old.channel);
+        assert_eq!(FIRST.load(Ordering::Relaxed), 1);
+
+        let new = SystemTimer::alarm(100, second).unwrap();
+        assert_eq!(new.channel, old.channel);
+        old.cancel();
+        fire(new.channel);
+        assert_eq!(SECOND.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn fire_clears_only_its_match() {
+        // Channel 2 is the GPU's, so no alarm test can be using it.
+        mock::reset();
+        fire(2);
+        assert_eq!(mock::writes(board::SYSTIMER_BASE, 4), [(0, 1 << 2)]);
+    }
+}
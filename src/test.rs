@@ -0,0 +1,43 @@
+/*
+ * test.rs
+ * In-kernel test runner.
+ *
+ * `cargo test` builds the kernel with every #[test_case] function and
+ * boots it in qemu with the runner from .cargo/config.toml. The tests
+ * run on core 0 after it is fully initialized. The results are printed
+ * on the console and the exit status goes back to cargo through
+ * semihosting: 0 if every test passed and 1 when one panics.
+ */
+
+use crate::{console, cpu, emergency_println, print, println, semihosting};
+
+// Testable is a test that prints its name and result.
+pub trait Testable {
+    fn run(&self);
+}
+
+impl<T: Fn()> Testable for T {
+    fn run(&self) {
+        print!("{} ... ", core::any::type_name::<T>());
+        self();
+        print!("ok\n");
+    }
+}
+
+// runner runs every test and exits qemu. A failing test panics,
+// and the panic handler calls failed.
+pub fn runner(tests: &[&dyn Testable]) {
+    println!("running {} tests", tests.len());
+    for test in tests {
+        test.run();
+    }
+    println!("test result: ok. {} passed", tests.len());
+    console::flush();
+    semihosting::exit(0);
+}
+
+// failed is called by the panic handler once the panic is reported.
+pub fn failed() -> ! {
+    emergency_println!("test result: FAILED");
+    semihosting::exit(1);
+}
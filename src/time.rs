@@ -0,0 +1,145 @@
+/*
+ * time.rs
+ * Monotonic time and per-core ticks from the ARM generic timer.
+ *
+ * CNTPCT_EL0 counts up at CNTFRQ_EL0 Hz on every core, and each core
+ * has its own physical timer (CNTP_*) that raises a local interrupt.
+ */
+
+use crate::cpu::{CntfrqEl0, CntpCtlEl0, CntpTvalEl0, CntpctEl0};
+use crate::irq::{self, Source};
+use crate::reg::{Reg, RegWrite};
+use crate::{board, cpu};
+#[cfg(target_os = "none")]
+use core::arch::asm;
+use core::ops::{Add, Sub};
+use core::sync::atomic::{AtomicU64, Ordering};
+pub use core::time::Duration;
+
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+
+// freq returns the counter frequency in Hz.
+fn freq() -> u64 {
+    CntfrqEl0::fetch().get_value()
+}
+
+fn ticks_to_duration(ticks: u64) -> Duration {
+    let nanos = ticks as u128 * NANOS_PER_SEC / freq() as u128;
+    Duration::new(
+        (nanos / NANOS_PER_SEC) as u64,
+        (nanos % NANOS_PER_SEC) as u32,
+    )
+}
+
+fn duration_to_ticks(d: Duration) -> u64 {
+    (d.as_nanos() * freq() as u128 / NANOS_PER_SEC) as u64
+}
+
+// Instant is a point in time as measured by the system counter.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Instant {
+    ticks: u64,
+}
+
+#[allow(dead_code)]
+impl Instant {
+    // duration_since returns the time from earlier to self, or zero if
+    // earlier is later.
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        ticks_to_duration(self.ticks.saturating_sub(earlier.ticks))
+    }
+
+    // elapsed returns the time since self.
+    pub fn elapsed(&self) -> Duration {
+        now().duration_since(*self)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, d: Duration) -> Instant {
+        Instant {
+            ticks: self.ticks + duration_to_ticks(d),
+        }
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+// now returns the current time.
+pub fn now() -> Instant {
+    // isb so the counter isn't read early, out of order.
+    #[cfg(target_os = "none")]
+    unsafe {
+        asm!("isb")
+    };
+    Instant {
+        ticks: CntpctEl0::fetch().get_value(),
+    }
+}
+
+// uptime returns the time since the counter started, at reset.
+pub fn uptime() -> Duration {
+    now().duration_since(Instant { ticks: 0 })
+}
+
+// sleep busy-waits for at least d.
+#[allow(dead_code)]
+pub fn sleep(d: Duration) {
+    let end = now() + d;
+    while now() < end {
+        core::hint::spin_loop();
+    }
+}
+
+static TICKS: [AtomicU64; board::NCPU] = [const { AtomicU64::new(0) }; board::NCPU];
+
+// ticks returns the number of timer ticks this core has taken.
+#[allow(dead_code)]
+pub fn ticks() -> u64 {
+    TICKS[cpu::core_id() as usize].load(Ordering::Relaxed)
+}
+
+fn tick_interval() -> u64 {
+    freq() / board::TICK_HZ
+}
+
+fn tick() {
+    CntpTvalEl0::new(tick_interval()).store();
+    TICKS[cpu::core_id() as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+// init_core starts the periodic tick on this core.
+pub fn init_core() {
+    CntpTvalEl0::new(tick_interval()).store();
+    CntpCtlEl0::zero().set_enable(true).set_imask(false).store();
+    // CNTP_*_EL0 is the EL1 physical timer at every EL, including
+    // EL3, and it raises nCNTPNSIRQ. Only CNTPS_*_EL1 raises CNTPSIRQ.
+    irq::register(Source::CNTPNS, tick);
+}
+
+#[cfg(all(test, target_os = "none"))]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn sleep_advances_now() {
+        let start = now();
+        sleep(Duration::from_millis(5));
+        assert!(start.elapsed() >= Duration::from_millis(5));
+    }
+
+    #[test_case]
+    fn ticks_roundtrip() {
+        let d = Duration::from_micros(1500);
+        let back = ticks_to_duration(duration_to_ticks(d));
+        assert!(d - back < Duration::from_micros(1));
+    }
+}
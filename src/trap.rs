@@ -0,0 +1,154 @@
+/*
+ * trap.rs
+ * Exception dispatch.
+ *
+ * Every vector in asm.rs saves the interrupted state in a TrapFrame
+ * on the stack and calls _trap_dispatch with it. Whatever the handler
+ * leaves in the frame is restored on return, so handlers can resume
+ * execution, skip or emulate instructions, or return values in x0.
+ */
+
+use crate::{board, cpu, emergency_println, irq};
+use core::fmt;
+use core::mem::size_of;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+
+// TrapFrame is the register state saved on exception entry.
+// Its layout is shared with _trap_entry in asm.rs.
+#[repr(C)]
+pub struct TrapFrame {
+    pub x: [u64; 31],
+    pub sp_el0: u64,
+    pub elr: u64,
+    pub spsr: u64,
+}
+
+pub const FRAME_SIZE: usize = size_of::<TrapFrame>();
+const _: () = assert!(FRAME_SIZE == 272 && FRAME_SIZE.is_multiple_of(16));
+
+// Source is where the exception was taken from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Source {
+    CurrentSp0,
+    CurrentSpx,
+    LowerAArch64,
+    LowerAArch32,
+}
+
+// Kind is the type of exception.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    Sync,
+    Irq,
+    Fiq,
+    SError,
+}
+
+// decode splits the vector number passed in from the vector table.
+fn decode(num: u64) -> (Source, Kind) {
+    let source = match num >> 4 {
+        0 => Source::CurrentSp0,
+        1 => Source::CurrentSpx,
+        2 => Source::LowerAArch64,
+        _ => Source::LowerAArch32,
+    };
+    let kind = match num & 0xf {
+        0 => Kind::Sync,
+        1 => Kind::Irq,
+        2 => Kind::Fiq,
+        _ => Kind::SError,
+    };
+    (source, kind)
+}
+
+// SyncHandler handles a synchronous exception, given the frame and the ESR.
+pub type SyncHandler = fn(&mut TrapFrame, u64);
+
+// SYNC_HANDLERS has a slot for each exception class (ESR bits 26..32).
+const NUM_EC: usize = 64;
+static SYNC_HANDLERS: Mutex<[Option<SyncHandler>; NUM_EC]> = Mutex::new([None; NUM_EC]);
+
+// register_sync installs handler for synchronous exceptions with class ec.
+#[allow(dead_code)]
+pub fn register_sync(ec: u64, handler: SyncHandler) {
+    SYNC_HANDLERS.lock()[ec as usize] = Some(handler);
+}
+
+impl fmt::Display for TrapFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "ELR {:016x} SPSR {:08x} SP_EL0 {:016x}",
+            self.elr, self.spsr, self.sp_el0
+        )?;
+        for (i, x) in self.x.iter().enumerate() {
+            if i % 4 == 0 {
+                writeln!(f)?;
+            } else {
+                write!(f, " ")?;
+            }
+            write!(f, "x{:<2} {:016x}", i, x)?;
+        }
+        Ok(())
+    }
+}
+
+// ACTIVE holds 1 + the vector number of the exception each core is
+// handling, or 0 if it isn't handling one.
+static ACTIVE: [AtomicU64; board::NCPU] = [const { AtomicU64::new(0) }; board::NCPU];
+
+// current returns the kind of exception this core is handling, if any.
+#[cfg(target_os = "none")]
+pub fn current() -> Option<Kind> {
+    match ACTIVE[cpu::core_id() as usize].load(Ordering::Relaxed) {
+        0 => None,
+        n => Some(decode(n - 1).1),
+    }
+}
+
+// _trap_dispatch is called by _trap_entry with the saved frame.
+#[no_mangle]
+pub extern "C" fn _trap_dispatch(num: u64, frame: &mut TrapFrame) {
+    let active = &ACTIVE[cpu::core_id() as usize];
+    let outer = active.swap(num + 1, Ordering::Relaxed);
+
+    let (_source, kind) = decode(num);
+    match kind {
+        Kind::Sync => {
+            let esr = cpu::esr();
+            let ec = (esr >> 26) & 0x3f;
+            let handler = SYNC_HANDLERS.lock()[ec as usize];
+            match handler {
+                Some(handler) => handler(frame, esr),
+                None => unhandled(num, frame),
+            }
+        }
+        Kind::Irq => irq::dispatch(),
+        _ => unhandled(num, frame),
+    }
+
+    active.store(outer, Ordering::Relaxed);
+}
+
+// unhandled reports an exception nobody claimed and panics.
+// The panic handler prints the decoded syndrome.
+fn unhandled(num: u64, frame: &TrapFrame) -> ! {
+    let (source, kind) = decode(num);
+    match kind {
+        Kind::Sync => emergency_println!(
+            "unhandled {} from {:?} at EL {}",
+            cpu::Esr::fetch().class(),
+            source,
+            cpu::current_el()
+        ),
+        _ => emergency_println!(
+            "unhandled {:?} exception from {:?} at EL {}",
+            kind,
+            source,
+            cpu::current_el()
+        ),
+    }
+    emergency_println!("{}", frame);
+    panic!("unhandled exception");
+}
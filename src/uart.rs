@@ -3,49 +3,125 @@
  * BCM2837 AUX UART (UART1) support.
  */
 
-use crate::reg::Reg;
-use crate::{board, define_bit, define_bit_wo, define_bits, gpio, mmio_reg32};
-use core::fmt;
-use lazy_static::lazy_static;
+use crate::console::Console;
+use crate::irq::{self, Source};
+use crate::mmio::Block;
+use crate::reg::{Reg, RegRead, RegWrite};
+use crate::ringbuf::RingBuffer;
+use crate::{asm, board, cpu, field_enum, gpio, reg_fields, register_block};
+use core::sync::atomic::{fence, AtomicBool, Ordering};
 use spin::Mutex;
 
-mmio_reg32!(AuxEnables, board::AUX_BASE + 4);
-mmio_reg32!(AuxMuIo, board::AUX_BASE + 0x40);
-mmio_reg32!(AuxMuIer, board::AUX_BASE + 0x44);
-mmio_reg32!(AuxMuIir, board::AUX_BASE + 0x48);
-mmio_reg32!(AuxMuLcr, board::AUX_BASE + 0x4c);
-mmio_reg32!(AuxMuMcr, board::AUX_BASE + 0x50);
-mmio_reg32!(AuxMuLsr, board::AUX_BASE + 0x54);
-mmio_reg32!(AuxMuCntl, board::AUX_BASE + 0x60);
-mmio_reg32!(AuxMuBaud, board::AUX_BASE + 0x68);
-
-impl AuxEnables {
-    define_bit!(0, set_enable, get_enable);
-}
+// The AUX block (both mini uart and the two SPIs) shares GPU IRQ 29.
+const AUX_IRQ: u32 = 29;
 
-impl AuxMuIir {
-    define_bit_wo!(1, set_clear_recv_fifo);
-    define_bit_wo!(2, set_clear_xmit_fifo);
-}
+register_block!(
+    // AuxRegs is the AUX block, with the mini uart's registers.
+    // The two SPI masters that follow them aren't used.
+    struct AuxRegs {
+        0x00 => irq: AuxIrq ro,
+        0x04 => enables: AuxEnables,
+        0x08 => _reserved0: [u32; 14],
+        0x40 => mu_io: AuxMuIo side_effects,
+        0x44 => mu_ier: AuxMuIer,
+        0x48 => mu_iir: AuxMuIir,
+        0x4c => mu_lcr: AuxMuLcr,
+        0x50 => mu_mcr: AuxMuMcr,
+        0x54 => mu_lsr: AuxMuLsr ro side_effects,
+        0x58 => mu_msr: AuxMuMsr ro,
+        0x5c => mu_scratch: AuxMuScratch,
+        0x60 => mu_cntl: AuxMuCntl,
+        0x64 => mu_stat: AuxMuStat ro,
+        0x68 => mu_baud: AuxMuBaud,
+    }
+);
 
-impl AuxMuLcr {
-    const DATA_SIZE_8B: u32 = 3;
+const AUX: Block<AuxRegs> = unsafe { Block::at(board::AUX_BASE) };
 
-    define_bits!(0, 2, u32, set_data_size, get_data_size);
-}
+reg_fields!(AuxIrq {
+    mini_uart: ro @ 0,
+    spi1: ro @ 1,
+    spi2: ro @ 2,
+});
 
-impl AuxMuLsr {
-    define_bit!(5, _set_tx_empty, get_tx_empty);
-}
+reg_fields!(AuxEnables {
+    enable: rw @ 0,
+});
 
-impl AuxMuCntl {
-    define_bit!(0, set_recv_enb, get_recv_enb);
-    define_bit!(1, set_xmit_enb, get_xmit_enb);
-}
+// The BCM2837 datasheet has the AUX_MU_IER bits swapped, bit 0 is
+// really the receive interrupt and bit 1 the transmit interrupt.
+reg_fields!(AuxMuIer {
+    recv_irq: rw @ 0,
+    xmit_irq: rw @ 1,
+});
+
+reg_fields!(AuxMuIo {
+    data: rw @ 0..8,
+});
+
+reg_fields!(AuxMuIir {
+    clear_recv_fifo: wo @ 1,
+    clear_xmit_fifo: wo @ 2,
+});
+
+field_enum!(
+    // DataSize is the number of data bits, in AUX_MU_LCR.
+    pub enum DataSize {
+        Seven = 0,
+        Eight = 3,
+    }
+);
+
+reg_fields!(AuxMuLcr {
+    data_size: rw @ 0..2 as DataSize,
+});
+
+reg_fields!(AuxMuMcr {
+    rts: rw @ 1,
+});
+
+reg_fields!(AuxMuLsr {
+    data_ready: ro @ 0,
+    rx_overrun: ro @ 1,
+    tx_empty: ro @ 5,
+    tx_idle: ro @ 6,
+});
+
+reg_fields!(AuxMuMsr {
+    cts: ro @ 5,
+});
+
+reg_fields!(AuxMuScratch {
+    scratch: rw @ 0..8,
+});
+
+reg_fields!(AuxMuCntl {
+    recv_enb: rw @ 0,
+    xmit_enb: rw @ 1,
+});
+
+reg_fields!(AuxMuStat {
+    symbol_available: ro @ 0,
+    space_available: ro @ 1,
+    rx_idle: ro @ 2,
+    tx_idle: ro @ 3,
+    rx_overrun: ro @ 4,
+    tx_full: ro @ 5,
+    rts: ro @ 6,
+    cts: ro @ 7,
+    tx_empty: ro @ 8,
+    tx_done: ro @ 9,
+    rx_fifo_level: ro @ 16..20,
+    tx_fifo_level: ro @ 24..28,
+});
+
+reg_fields!(AuxMuBaud {
+    divisor: rw @ 0..16,
+});
 
 impl AuxMuBaud {
     fn set_baud(&mut self, baud: u32) -> &mut Self {
-        self.set_bits(0, 16, (board::AUX_UART_CLOCK / (baud * 8)) - 1)
+        self.set_divisor((board::AUX_UART_CLOCK / (baud * 8)) - 1)
     }
 }
 
@@ -54,60 +130,269 @@ fn init() {
     gpio::pin_use_as_alt5(board::AUX_UART_TX_PIN);
     gpio::pin_use_as_alt5(board::AUX_UART_RX_PIN);
 
-    AuxEnables::zero().set_enable(true).store(); // uart enabled
-    AuxMuIer::new(0).store(); // reset interupts
-    AuxMuCntl::new(0).store(); // recv/xmit disabled
-    AuxMuLcr::new(0).set_data_size(AuxMuLcr::DATA_SIZE_8B).store(); // 8bit mode
-    AuxMuMcr::new(0).store(); // reset interupts
-    AuxMuIer::new(0).store(); // reset interupts again
-    AuxMuIir::new(0).set_clear_recv_fifo(true).set_clear_xmit_fifo(true).store(); // clear both fifos
-    AuxMuBaud::zero().set_baud(115200).store();
-    AuxMuCntl::zero().set_recv_enb(true).set_xmit_enb(true).store(); // recv/xmit enabled
+    AUX.enables().modify(|r| r.set_enable(true)); // uart enabled, leaving the SPIs alone
+    AUX.mu_ier().store(); // reset interupts
+    AUX.mu_cntl().store(); // recv/xmit disabled
+    AUX.mu_lcr().set_data_size(DataSize::Eight).store(); // 8bit mode
+    AUX.mu_mcr().store(); // reset interupts
+    AUX.mu_ier().store(); // reset interupts again
+    XMIT_IRQ.store(false, Ordering::Relaxed);
+    AUX.mu_iir().set_clear_recv_fifo(true).set_clear_xmit_fifo(true).store(); // clear both fifos
+    AUX.mu_baud().set_baud(115200).store();
+    AUX.mu_cntl().set_recv_enb(true).set_xmit_enb(true).store(); // recv/xmit enabled
 }
 
 // write_char writes a single character. It uses polling to wait
 // for the uart to be writable.
 fn write_char(ch: u8) {
-    let mut aux_mu_lsr = AuxMuLsr::zero();
-    while !aux_mu_lsr.fetch().get_tx_empty() { /* wait */ }
-    AuxMuIo::new(ch as u32).store();
+    AUX.mu_lsr().wait_until(|r| r.tx_empty());
+    AUX.mu_io().write(|w| w.set_value(ch as u32));
 }
 
-pub struct Writer {
-    initialized: bool,
+// RX holds received bytes until they are read.
+static RX: RingBuffer<1024> = RingBuffer::new();
+
+// TX holds bytes waiting to be sent by the transmit interrupt.
+// The writer (under console::WRITER) is its only producer, and its
+// consumers (the IRQ handler and flush) take TX_DRAIN.
+static TX: RingBuffer<4096> = RingBuffer::new();
+static TX_DRAIN: Mutex<()> = Mutex::new(());
+
+// TX_IRQ is set once the transmit interrupt can be used.
+static TX_IRQ: AtomicBool = AtomicBool::new(false);
+
+// IER is shared by the writer and the IRQ handler, which may run on
+// different cores. It is only updated under IER_LOCK with IRQs masked.
+static IER_LOCK: Mutex<()> = Mutex::new(());
+
+// XMIT_IRQ is whether the transmit interrupt is on. It is only changed
+// under IER_LOCK, but the writer reads it without the lock so it only
+// takes the lock when the interrupt needs turning on.
+static XMIT_IRQ: AtomicBool = AtomicBool::new(false);
+
+fn set_xmit_irq(enable: bool) {
+    cpu::without_irqs(|| {
+        let _ier = IER_LOCK.lock();
+        if XMIT_IRQ.load(Ordering::Relaxed) != enable {
+            AUX.mu_ier().modify(|r| r.set_xmit_irq(enable));
+            XMIT_IRQ.store(enable, Ordering::Relaxed);
+        }
+    });
 }
 
-lazy_static! {
-    pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer { initialized: false });
+// want_xmit_irq turns on the transmit interrupt after bytes are queued,
+// unless it is already on.
+fn want_xmit_irq() {
+    // Pairs with the fence in drain: either drain sees the new bytes
+    // after turning the interrupt off, or we see that it is off.
+    fence(Ordering::SeqCst);
+    if !XMIT_IRQ.load(Ordering::Relaxed) {
+        set_xmit_irq(true);
+    }
 }
 
-impl fmt::Write for Writer {
-    fn write_str(&mut self, s: &str) -> fmt::Result {
-        if !self.initialized {
-            self.initialized = true;
-            init();
+// drain sends queued bytes while the transmit fifo has room.
+// When the queue empties it turns off the transmit interrupt.
+fn drain() {
+    let _drain = match TX_DRAIN.try_lock() {
+        Some(guard) => guard,
+        None => return, // someone else is already draining
+    };
+    loop {
+        while AUX.mu_lsr().fetch().tx_empty() {
+            match TX.pop() {
+                Some(ch) => AUX.mu_io().set_value(ch as u32).store(),
+                None => break,
+            }
         }
-
-        for ch in s.bytes() {
-            write_char(ch);
+        if !TX.is_empty() {
+            return; // fifo is full, wait for the next interrupt
         }
-        Ok(())
+        set_xmit_irq(false);
+        fence(Ordering::SeqCst);
+        // Catch bytes queued after the check, before the irq went off.
+        if TX.is_empty() {
+            return;
+        }
+        set_xmit_irq(true);
     }
 }
 
-#[macro_export]
-macro_rules! print {
-    ($($arg:tt)*) => ($crate::uart::_print(format_args!($($arg)*)));
+// flush synchronously sends everything queued in TX.
+fn flush() {
+    let _drain = TX_DRAIN.lock();
+    while let Some(ch) = TX.pop() {
+        write_char(ch);
+    }
 }
 
-#[macro_export]
-macro_rules! println {
-    () => ($crate::print!("\n"));
-    ($($arg:tt)*) => ($crate::print!("core {}: {}\n", cpu::core_id(), format_args!($($arg)*)));
+// handle_irq moves everything in the receive FIFO into RX and
+// refills the transmit FIFO from TX.
+// Received bytes are dropped if RX is full.
+fn handle_irq() {
+    while AUX.mu_lsr().fetch().data_ready() {
+        let ch = AUX.mu_io().fetch().get_value() as u8;
+        RX.push(ch);
+    }
+    asm::sev(); // wake readers
+    drain();
 }
 
-#[doc(hidden)]
-pub fn _print(args: fmt::Arguments) {
-    use core::fmt::Write;
-    WRITER.lock().write_fmt(args).unwrap();
+// AuxUart is the mini uart as a console.
+pub struct AuxUart;
+
+pub static AUX_UART: AuxUart = AuxUart;
+
+impl Console for AuxUart {
+    fn init(&self) {
+        init();
+    }
+
+    fn init_irq(&self) {
+        irq::register(Source::Gpu(AUX_IRQ), handle_irq);
+        AUX.mu_ier().write(|w| w.set_recv_irq(true));
+        TX_IRQ.store(true, Ordering::Release);
+    }
+
+    // write_byte queues ch for the transmit interrupt. Before interrupts
+    // are set up, and whenever they are masked (in exception handlers and
+    // panics), it writes synchronously instead, after flushing the queue.
+    fn write_byte(&self, ch: u8) {
+        if !TX_IRQ.load(Ordering::Acquire) || cpu::irqs_masked() {
+            flush();
+            write_char(ch);
+            return;
+        }
+        while !TX.push(ch) {
+            // Full, let the interrupt make room.
+            want_xmit_irq();
+            core::hint::spin_loop();
+        }
+        want_xmit_irq();
+    }
+
+    fn flush(&self) {
+        flush();
+        AUX.mu_lsr().wait_until(|r| r.tx_idle());
+    }
+
+    fn try_read_byte(&self) -> Option<u8> {
+        RX.pop()
+    }
+
+    fn recover(&self) {
+        if !AUX.enables().read(|r| r.enable()) || !AUX.mu_cntl().read(|r| r.xmit_enb()) {
+            init();
+        }
+    }
+
+    // emergency_write_byte sends any queued output first, unless the
+    // queue is being drained by someone who may never finish.
+    fn emergency_write_byte(&self, ch: u8) {
+        if let Some(_drain) = TX_DRAIN.try_lock() {
+            while let Some(ch) = TX.pop() {
+                write_char(ch);
+            }
+        }
+        write_char(ch);
+    }
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+    use crate::mock::{self, Access, Loc};
+
+    #[test]
+    fn init_programs_aux_registers_in_order() {
+        let baud = (board::AUX_UART_CLOCK / (8 * 115200) - 1) as u64;
+        mock::reset();
+        init();
+        assert_eq!(
+            mock::writes(board::AUX_BASE, 0x100),
+            [
+                (0x04, 1),    // AUX_ENABLES: mini uart on
+                (0x44, 0),    // IER: no interrupts
+                (0x60, 0),    // CNTL: rx/tx off
+                (0x4c, 3),    // LCR: 8 bit
+                (0x50, 0),    // MCR
+                (0x44, 0),    // IER
+                (0x48, 6),    // IIR: clear both fifos
+                (0x68, baud), // BAUD
+                (0x60, 3),    // CNTL: rx/tx on
+            ]
+        );
+
+        // TXD1/RXD1 are ALT5 on GPIO 14 and 15.
+        let fsel1 = mock::value(Loc::Mmio(board::GPIO_BASE + 4));
+        assert_eq!(fsel1 >> 12 & 0o77, 0o22);
+    }
+
+    #[test]
+    fn write_char_checks_tx_empty_before_writing() {
+        let lsr = Loc::Mmio(board::AUX_BASE + 0x54);
+        let io = Loc::Mmio(board::AUX_BASE + 0x40);
+        mock::reset();
+        mock::preset(lsr, 1 << 5);
+        write_char(b'x');
+        assert_eq!(
+            mock::take_log(),
+            [Access::Read(lsr, 1 << 5), Access::Write(io, b'x' as u64)]
+        );
+    }
+
+    #[test]
+    fn flush_waits_for_the_transmitter_to_go_idle() {
+        let lsr = Loc::Mmio(board::AUX_BASE + 0x54);
+        mock::reset();
+        mock::preset(lsr, 1 << 5);
+        mock::preset_after(lsr, 2, 1 << 5 | 1 << 6);
+        AuxUart.flush();
+        assert_eq!(
+            mock::take_log(),
+            [
+                Access::Read(lsr, 1 << 5),
+                Access::Read(lsr, 1 << 5),
+                Access::Read(lsr, 1 << 5 | 1 << 6),
+            ]
+        );
+    }
+
+    #[test]
+    fn aux_registers_follow_the_block_base() {
+        mock::reset();
+        let aux: Block<AuxRegs> = unsafe { Block::at(0x1000) };
+        aux.mu_baud().set_value(270).store();
+        aux.enables().set_enable(true).store();
+        assert_eq!(mock::writes(0x1000, 0x100), [(0x68, 270), (0x04, 1)]);
+    }
+
+    #[test]
+    fn dump_skips_registers_with_read_side_effects() {
+        mock::reset();
+        mock::preset(Loc::Mmio(board::AUX_BASE + 0x54), 1 << 5); // console tx_empty
+        let aux: Block<AuxRegs> = unsafe { Block::at(0x1000) };
+        crate::dump!(aux);
+
+        let mut reads = Vec::new();
+        let mut out = String::new();
+        for access in mock::take_log() {
+            match access {
+                Access::Read(Loc::Mmio(addr), _) if (0x1000..0x1100).contains(&addr) => {
+                    reads.push(addr - 0x1000)
+                }
+                Access::Write(Loc::Mmio(addr), ch) if addr == board::AUX_BASE + 0x40 => {
+                    out.push(ch as u8 as char)
+                }
+                _ => {}
+            }
+        }
+        assert_eq!(
+            reads,
+            [0x00, 0x04, 0x44, 0x48, 0x4c, 0x50, 0x58, 0x5c, 0x60, 0x64, 0x68]
+        );
+        assert!(out.starts_with("AuxRegs at 0x1000:\n"));
+        assert!(out.contains("  0x068 AuxMuBaud { value: 0x0, divisor: 0x0 }\n"));
+        assert!(!out.contains("AuxMuIo"));
+    }
 }